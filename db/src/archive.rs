@@ -0,0 +1,102 @@
+//! Txs don't reference digests by a foreign key so that old digests can be
+//! moved out of the `digests` table while the txs stay.
+//!
+//! ```sql
+//! CREATE TABLE digests_archive (
+//!     id BIGINT PRIMARY KEY,
+//!     digest BYTEA NOT NULL UNIQUE,
+//!     position BIGINT NOT NULL UNIQUE
+//! );
+//! ```
+//!
+//! Archived digests keep their ids and positions. New digests are checked
+//! against the archive too, so that e.g. a backfill of an old seq# range
+//! doesn't store them again.
+
+use crate::Pool;
+use anyhow::{Context, Result};
+
+/// Returns the highest digest id which is safe to archive, i.e.
+/// 1. it's not one of the `keep_latest` digests with the highest ids;
+/// 2. all jobs of every kind with a watermark are finished up to it, see
+/// [`crate::queue::advance_watermark`];
+/// 3. no pending job of any kind is at or below it;
+/// 4. every kind with an enqueue cursor has a job for it, even a kind which
+/// has no watermark yet, see [`crate::queue::enqueue_from_digests`];
+/// 5. every relay has published it, see [`crate::offsets`].
+///
+/// Returns [`None`] if there are no digests or no watermarks. Without a
+/// watermark we don't know what the consumers of digests still need.
+pub async fn select_archivable_until(
+    db: &Pool,
+    keep_latest: i64,
+) -> Result<Option<i64>> {
+    // LEAST ignores NULLs, hence the checks that the tables aren't empty
+    let query = "
+        SELECT LEAST(
+            (SELECT MAX(id) FROM digests) - $1,
+            (SELECT MIN(digest_id) FROM watermarks),
+            (SELECT MIN(digest_id) - 1 FROM jobs WHERE status = 0),
+            -- positions and ids are assigned in the same order
+            (
+                SELECT id - 1 FROM digests
+                WHERE position > (SELECT MIN(position) FROM enqueue_cursors)
+                ORDER BY position ASC LIMIT 1
+            ),
+            (
+                SELECT id - 1 FROM digests
+                WHERE position > (SELECT MIN(position) FROM relay_offsets)
+                ORDER BY position ASC LIMIT 1
+            )
+        ) AS until_id
+        WHERE
+            EXISTS (SELECT 1 FROM digests)
+            AND EXISTS (SELECT 1 FROM watermarks)";
+
+    let row = db
+        .get()
+        .await?
+        .query_opt(query, &[&keep_latest])
+        .await
+        .context("Cannot select archivable digest id")?;
+
+    Ok(row.map(|row| row.try_get("until_id")).transpose()?)
+}
+
+/// Moves up to `limit` digests with the lowest ids, up to given id inclusive,
+/// to the archive. Returns how many were moved.
+///
+/// This is a single statement, a digest is never in both tables nor in
+/// neither.
+pub async fn archive_digests_until(
+    db: &Pool,
+    until_id: i64,
+    limit: i64,
+) -> Result<u64> {
+    let query = "
+        WITH moved AS (
+            DELETE FROM
+                digests
+            WHERE
+                id IN (
+                    SELECT id FROM digests WHERE id <= $1
+                    ORDER BY id ASC LIMIT $2
+                )
+            RETURNING
+                id, digest, position
+        )
+        INSERT INTO digests_archive
+            (id, digest, position)
+        SELECT
+            id, digest, position
+        FROM
+            moved";
+
+    db.get()
+        .await?
+        .execute(query, &[&until_id, &limit])
+        .await
+        .with_context(|| {
+            format!("Cannot archive digests up to id {}", until_id)
+        })
+}
@@ -1,35 +1,97 @@
 //! Wraps around database queries.
 //!
-//! TODO: prepare statements where relevant
+//! Services share a [`Pool`] of connections. Broken connections are replaced
+//! when they're checked out of the pool, so a failed query can simply be
+//! retried. Statements of the hot queries are prepared once per connection
+//! and cached.
+//!
+//! Fns which make sense as a part of a transaction take a generic client,
+//! the rest take the pool. The core of them is also behind the [`Storage`]
+//! trait, which has an in-memory implementation.
+//!
+//! # Ordering
+//!
+//! The `id` of a digest comes from a sequence, which leaves gaps on rolled
+//! back inserts, and a reader might see a higher id committed before a lower
+//! one. Consumers which need a well-defined order therefore read digests by
+//! their `position` instead.
+//!
+//! ```sql
+//! CREATE TABLE digests (
+//!     id BIGSERIAL PRIMARY KEY,
+//!     digest BYTEA NOT NULL UNIQUE,
+//!     -- gapless, starting at 0
+//!     position BIGINT NOT NULL UNIQUE
+//! );
+//!
+//! -- single row with the position of the next digest
+//! CREATE TABLE digest_positions (next BIGINT NOT NULL);
+//! INSERT INTO digest_positions
+//!     SELECT COUNT(*) FROM digests;
+//! ```
+//!
+//! To add positions to existing digests, set them to
+//! `ROW_NUMBER() OVER (ORDER BY id) - 1` before creating the counter.
+//!
+//! Writers lock the counter row for the duration of the insert, so positions
+//! are assigned without gaps and are committed in ascending order. A consumer
+//! which reads digests after the last position it has seen, see
+//! [`select_digests_after_position`], never skips a digest which is yet to be
+//! committed. If [`position_holes`] finds a hole, the digests have been
+//! moved to the archive, see [`archive`], not lost.
 
+pub mod archive;
+mod memory;
 mod models;
+pub mod offsets;
+pub mod partitions;
+pub mod queue;
+mod storage;
+mod tls;
 
+pub use deadpool_postgres::{GenericClient as GenericDbClient, Pool};
+pub use memory::MemoryStorage;
 pub use models::SuiTx;
+pub use storage::{PgStorage, Storage, StorageBackend};
+pub use tls::TlsConf;
 
 use anyhow::{Context, Result};
-use itertools::Itertools;
-use log::error;
+use deadpool_postgres::{Manager, ManagerConfig, RecyclingMethod, Transaction};
+use futures::pin_mut;
 use misc::Digest;
 use models::Clusivity;
-use std::ops::Not;
-use tokio_postgres::{Client as DbClient, GenericClient as GenericDbClient};
+use std::ops::{Not, RangeInclusive};
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::Type;
 
-/// See the documentation for [`tokio_postgres::connect`] for details.
-pub async fn connect(conn_conf: &str) -> Result<DbClient> {
-    let tls = tokio_postgres::NoTls;
-    let (client, conn) = tokio_postgres::connect(conn_conf, tls).await?;
+/// Up to this many digests, [`bulk_insert_digests`] uses a single
+/// [`insert_digests`] because creating the staging table isn't worth it.
+const COPY_DIGESTS_THRESHOLD: usize = 1_000;
+/// [`bulk_insert_digests`] copies at most this many digests in one
+/// transaction so that a huge batch doesn't hold the staging table and locks
+/// for too long.
+const COPY_DIGESTS_CHUNK: usize = 50_000;
 
-    tokio::spawn(async move {
-        if let Err(e) = conn.await {
-            error!("db connection error: {}", e);
-        }
-    });
+/// See the documentation for [`tokio_postgres::Config`] for details on the
+/// format of the connection conf. Connections are opened lazily.
+pub fn pool(conn_conf: &str, max_size: usize, tls: &TlsConf) -> Result<Pool> {
+    let pg_conf: tokio_postgres::Config =
+        conn_conf.parse().context("Invalid db connection conf")?;
+    let manager_conf = ManagerConfig {
+        // checks whether the connection is closed, without a round trip
+        recycling_method: RecyclingMethod::Fast,
+    };
+    let manager = if tls.enabled {
+        Manager::from_config(pg_conf, tls.connector()?, manager_conf)
+    } else {
+        Manager::from_config(pg_conf, tokio_postgres::NoTls, manager_conf)
+    };
 
-    Ok(client)
+    Ok(Pool::builder(manager).max_size(max_size).build()?)
 }
 
 pub async fn select_digests_since_exclusive(
-    db: &DbClient,
+    db: &Pool,
     digest: &Digest,
     limit: usize,
 ) -> Result<Vec<Digest>> {
@@ -37,7 +99,7 @@ pub async fn select_digests_since_exclusive(
 }
 
 pub async fn select_digests_since_inclusive(
-    db: &DbClient,
+    db: &Pool,
     digest: &Digest,
     limit: usize,
 ) -> Result<Vec<Digest>> {
@@ -45,157 +107,599 @@ pub async fn select_digests_since_inclusive(
 }
 
 async fn select_digests_since(
-    db: &DbClient,
+    db: &Pool,
     digest: &Digest,
     clusivity: Clusivity,
     limit: usize,
 ) -> Result<Vec<Digest>> {
-    let statement = format!(
-        "SELECT
-            digest
-        FROM
-            digests
-        WHERE id {} (SELECT id FROM digests WHERE digest = ?)
-        ORDER BY
-            id
-        ASC LIMIT {}",
-        if matches!(clusivity, Clusivity::In) {
-            ">="
-        } else {
-            ">"
-        },
-        limit
-    );
+    let query = match clusivity {
+        Clusivity::In => {
+            "
+            SELECT
+                digest
+            FROM
+                digests
+            WHERE id >= (SELECT id FROM digests WHERE digest = $1)
+            ORDER BY
+                id
+            ASC LIMIT $2"
+        }
+        Clusivity::Ex => {
+            "
+            SELECT
+                digest
+            FROM
+                digests
+            WHERE id > (SELECT id FROM digests WHERE digest = $1)
+            ORDER BY
+                id
+            ASC LIMIT $2"
+        }
+    };
 
-    let rows = db
-        .query(&statement, &[digest])
+    let client = db.get().await?;
+    let statement = client.prepare_cached(query).await?;
+    let rows = client
+        .query(&statement, &[digest, &(limit as i64)])
         .await
-        .with_context(|| format!("Cannot select digests since {:?}", digest))?;
+        .with_context(|| format!("Cannot select digests since {}", digest))?;
 
     Ok(rows
         .into_iter()
         .map(|row| {
-            row.try_get::<_, Vec<u8>>("digest")
+            row.try_get::<_, Digest>("digest")
                 .expect("No column 'digest' in 'digests' table")
         })
         .collect())
 }
 
-pub async fn has_digest(db: &DbClient, digest: &Digest) -> Result<bool> {
-    Ok(db
-        .query("SELECT id FROM digests WHERE digest = ?", &[digest])
-        .await?
-        .is_empty()
-        .not())
+pub async fn has_digest(db: &Pool, digest: &Digest) -> Result<bool> {
+    let client = db.get().await?;
+    let statement = client
+        .prepare_cached("SELECT id FROM digests WHERE digest = $1")
+        .await?;
+
+    Ok(client.query(&statement, &[digest]).await?.is_empty().not())
 }
 
-/// Batch inserts digests in given order. On conflict (digests must be unique)
-/// it skips given digest.
-pub async fn insert_digests(db: &DbClient, digests: &[Digest]) -> Result<()> {
-    let query = insert_digest_query(digests.len());
+/// Returns those of given digests which are stored in db along with their ids.
+/// The result is ordered by id.
+pub async fn select_stored_digests(
+    db: &Pool,
+    digests: &[Digest],
+) -> Result<Vec<(i64, Digest)>> {
+    let query = "
+        SELECT
+            id, digest
+        FROM
+            digests
+        WHERE
+            digest = ANY($1)
+        ORDER BY
+            id ASC";
 
-    db.execute_raw(&query, digests)
+    let rows = db
+        .get()
+        .await?
+        .query(query, &[&digests])
         .await
-        .context("Cannot insert digests")?;
+        .context("Cannot select stored digests")?;
 
-    Ok(())
-}
-
-fn insert_digest_query(digests_count: usize) -> String {
-    assert_ne!(digests_count, 0, "Attempted to insert 0 digests");
-    format!(
-        "INSERT INTO digests (digest) VALUES ({}) ON CONFLICT DO NOTHING",
-        (0..digests_count).map(|_| "?").join(","),
-    )
+    rows.into_iter()
+        .map(|row| Ok((row.try_get("id")?, row.try_get("digest")?)))
+        .collect()
 }
 
-/// Postgres can be used to an extend as a job queue. Unprocessed digests have
-/// status 0.
-///
-/// See the `tx-puller` crate for more info.
-pub async fn select_and_lock_unprocessed_digests(
-    db: &impl GenericDbClient,
-    limit: usize,
+/// Selects digests with id between given ids inclusive, ordered by id.
+pub async fn select_digests_in_id_range(
+    db: &Pool,
+    from_id: i64,
+    to_id: i64,
 ) -> Result<Vec<(i64, Digest)>> {
-    let query = format!(
-        "
+    let query = "
         SELECT
             id, digest
         FROM
             digests
         WHERE
-            status = 0
-        LIMIT {} FOR UPDATE SKIP LOCKED;",
-        limit
-    );
+            id BETWEEN $1 AND $2
+        ORDER BY
+            id ASC";
 
-    let rows = db.query(&query, &[]).await?;
+    let rows = db
+        .get()
+        .await?
+        .query(query, &[&from_id, &to_id])
+        .await
+        .with_context(|| {
+            format!("Cannot select digests with ids {}..={}", from_id, to_id)
+        })?;
 
     rows.into_iter()
         .map(|row| Ok((row.try_get("id")?, row.try_get("digest")?)))
         .collect()
 }
 
-/// Given list of ids, set their status to 1.
+/// Batch inserts digests in given order, assigning them the next positions.
+/// Digests which are already stored or archived, or repeated in the batch, are
+/// skipped.
+pub async fn insert_digests(db: &Pool, digests: &[Digest]) -> Result<()> {
+    assert!(!digests.is_empty(), "Attempted to insert 0 digests");
+
+    // positions and ids are assigned in the order of the selected rows
+    let query = "
+        INSERT INTO digests
+            (digest, position)
+        SELECT
+            digest, $2 + ROW_NUMBER() OVER (ORDER BY ordinality ASC) - 1
+        FROM (
+            SELECT
+                digest, MIN(ordinality) AS ordinality
+            FROM
+                UNNEST($1::BYTEA[]) WITH ORDINALITY AS t(digest, ordinality)
+            WHERE NOT EXISTS (
+                SELECT 1 FROM digests d WHERE d.digest = t.digest
+            ) AND NOT EXISTS (
+                SELECT 1 FROM digests_archive a WHERE a.digest = t.digest
+            )
+            GROUP BY
+                digest
+        ) AS new
+        ORDER BY
+            ordinality ASC";
+
+    let mut client = db.get().await?;
+    let tx = client.transaction().await?;
+    let next_position = lock_next_position(&tx).await?;
+
+    let statement = tx.prepare_cached(query).await?;
+    let inserted = tx
+        .execute(&statement, &[&digests, &next_position])
+        .await
+        .context("Cannot insert digests")?;
+
+    advance_next_position(&tx, inserted).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Like [`insert_digests`], but large batches are streamed with COPY into a
+/// staging table, in chunks of [`COPY_DIGESTS_CHUNK`] digests. Order of the
+/// digests is kept and those which are stored already are skipped.
 ///
-///  See also [`select_and_lock_unprocessed_digests`].
-pub async fn mark_digests_as_processed(
-    db: &impl GenericDbClient,
-    ids_to_mark_processed: &[i64],
+/// Chunks are committed one by one, so if this fn fails some of the digests
+/// might be stored already. Calling it again with the same digests is fine.
+pub async fn bulk_insert_digests(db: &Pool, digests: &[Digest]) -> Result<()> {
+    if digests.len() <= COPY_DIGESTS_THRESHOLD {
+        return insert_digests(db, digests).await;
+    }
+
+    for chunk in digests.chunks(COPY_DIGESTS_CHUNK) {
+        copy_digests(db, chunk).await.with_context(|| {
+            format!("Cannot copy chunk of {} digests", chunk.len())
+        })?;
+    }
+
+    Ok(())
+}
+
+async fn copy_digests(db: &Pool, digests: &[Digest]) -> Result<()> {
+    let mut client = db.get().await?;
+    let tx = client.transaction().await?;
+
+    // the staging table is private to the connection and dropped on commit,
+    // therefore the statements below aren't cached
+    tx.batch_execute(
+        "
+        CREATE TEMP TABLE digests_staging
+            (ordinality BIGINT NOT NULL, digest BYTEA NOT NULL)
+        ON COMMIT DROP",
+    )
+    .await
+    .context("Cannot create digests staging table")?;
+
+    // copying doesn't need the lock yet, so we take it as late as possible
+    let sink = tx
+        .copy_in("COPY digests_staging (ordinality, digest) FROM STDIN BINARY")
+        .await?;
+    let writer = BinaryCopyInWriter::new(sink, &[Type::INT8, Type::BYTEA]);
+    pin_mut!(writer);
+    for (ordinality, digest) in digests.iter().enumerate() {
+        writer
+            .as_mut()
+            .write(&[&(ordinality as i64), digest])
+            .await
+            .context("Cannot copy digests into staging table")?;
+    }
+    writer.finish().await?;
+
+    let next_position = lock_next_position(&tx).await?;
+
+    // positions and ids are assigned in the order of the selected rows
+    let inserted = tx
+        .execute(
+            "
+            INSERT INTO digests
+                (digest, position)
+            SELECT
+                digest, $1 + ROW_NUMBER() OVER (ORDER BY ordinality ASC) - 1
+            FROM (
+                SELECT
+                    digest, MIN(ordinality) AS ordinality
+                FROM
+                    digests_staging s
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM digests d WHERE d.digest = s.digest
+                ) AND NOT EXISTS (
+                    SELECT 1 FROM digests_archive a WHERE a.digest = s.digest
+                )
+                GROUP BY
+                    digest
+            ) AS new
+            ORDER BY
+                ordinality ASC",
+            &[&next_position],
+        )
+        .await
+        .context("Cannot insert digests from staging table")?;
+
+    advance_next_position(&tx, inserted).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Locks the position counter until the end of given transaction and returns
+/// the position of the next digest.
+///
+/// Since this serializes all writers, the statements which follow in the
+/// transaction see every digest committed by the previous writers. That's why
+/// inserting digests doesn't need to handle conflicts, and why positions are
+/// committed in ascending order.
+async fn lock_next_position(tx: &Transaction<'_>) -> Result<i64> {
+    let statement = tx
+        .prepare_cached("SELECT next FROM digest_positions FOR UPDATE")
+        .await?;
+    let row = tx
+        .query_one(&statement, &[])
+        .await
+        .context("Cannot lock digest positions")?;
+
+    Ok(row.try_get("next")?)
+}
+
+async fn advance_next_position(
+    tx: &Transaction<'_>,
+    inserted: u64,
 ) -> Result<()> {
+    let statement = tx
+        .prepare_cached("UPDATE digest_positions SET next = next + $1")
+        .await?;
+    tx.execute(&statement, &[&(inserted as i64)])
+        .await
+        .context("Cannot advance digest positions")?;
+
+    Ok(())
+}
+
+/// Selects digests with position greater than given one, ordered by position.
+///
+/// See the module docs on how to tell holes in the returned positions.
+pub async fn select_digests_after_position(
+    db: &Pool,
+    position: i64,
+    limit: usize,
+) -> Result<Vec<(i64, Digest)>> {
     let query = "
-        UPDATE
+        SELECT
+            position, digest
+        FROM
             digests
-        SET
-            status = 1
         WHERE
-            id IN (?)";
+            position > $1
+        ORDER BY
+            position ASC
+        LIMIT $2";
 
-    db.execute_raw(query, ids_to_mark_processed).await?;
+    let client = db.get().await?;
+    let statement = client.prepare_cached(query).await?;
+    let rows = client
+        .query(&statement, &[&position, &(limit as i64)])
+        .await
+        .with_context(|| {
+            format!("Cannot select digests after position {}", position)
+        })?;
 
-    Ok(())
+    rows.into_iter()
+        .map(|row| Ok((row.try_get("position")?, row.try_get("digest")?)))
+        .collect()
+}
+
+/// Returns ranges of positions missing in given ascending positions, which
+/// are expected to follow right after `last_position`.
+pub fn position_holes(
+    last_position: i64,
+    positions: &[i64],
+) -> Vec<RangeInclusive<i64>> {
+    let mut expected = last_position + 1;
+    let mut holes = Vec::new();
+    for &position in positions {
+        if position > expected {
+            holes.push(expected..=(position - 1));
+        }
+        expected = expected.max(position + 1);
+    }
+
+    holes
 }
 
+/// Partitions for the orders of given txs must exist, see
+/// [`partitions::create_txs_partitions`].
 pub async fn insert_txs(
     db: &impl GenericDbClient,
     txs: &[SuiTx],
 ) -> Result<()> {
-    let query = format!(
-        "INSERT INTO txs (order, digest, version, data) VALUES ({})",
-        (0..txs.len()).map(|_| "?").join(","),
-    );
+    if txs.is_empty() {
+        return Ok(());
+    }
 
-    db.execute_raw(&query, txs)
+    let query = r#"
+        INSERT INTO txs
+            ("order", digest, version, data)
+        SELECT
+            *
+        FROM
+            UNNEST($1::BIGINT[], $2::BYTEA[], $3::TEXT[], $4::BYTEA[])"#;
+
+    let orders: Vec<_> = txs.iter().map(|tx| tx.order).collect();
+    let digests: Vec<_> = txs.iter().map(|tx| &tx.digest).collect();
+    let versions: Vec<_> = txs.iter().map(|tx| &tx.version).collect();
+    let data: Vec<_> = txs.iter().map(|tx| &tx.data).collect();
+
+    let statement = db.prepare_cached(query).await?;
+    db.execute(&statement, &[&orders, &digests, &versions, &data])
         .await
         .context("Cannot insert txs")?;
 
     Ok(())
 }
 
+/// Selects up to `limit` txs with order between given orders inclusive,
+/// ordered by order.
+pub async fn select_txs_in_order_range(
+    db: &Pool,
+    from_order: i64,
+    to_order: i64,
+    limit: i64,
+) -> Result<Vec<SuiTx>> {
+    let query = r#"
+        SELECT
+            "order", digest, version, data
+        FROM
+            txs
+        WHERE
+            "order" BETWEEN $1 AND $2
+        ORDER BY
+            "order" ASC
+        LIMIT $3"#;
+
+    let rows = db
+        .get()
+        .await?
+        .query(query, &[&from_order, &to_order, &limit])
+        .await
+        .with_context(|| {
+            format!(
+                "Cannot select txs with orders {}..={}",
+                from_order, to_order
+            )
+        })?;
+
+    rows.into_iter()
+        .map(|row| {
+            Ok(SuiTx {
+                order: row.try_get("order")?,
+                digest: row.try_get("digest")?,
+                version: row.try_get("version")?,
+                data: row.try_get("data")?,
+            })
+        })
+        .collect()
+}
+
+/// Inserts those of given txs whose order is not stored yet, and returns how
+/// many were inserted.
+///
+/// Meant for reprocessing history, where some of the txs might have been
+/// inserted by the puller already, possibly at the same time.
+pub async fn insert_missing_txs(db: &Pool, txs: &[SuiTx]) -> Result<usize> {
+    if txs.is_empty() {
+        return Ok(0);
+    }
+    partitions::create_txs_partitions(db, txs.iter().map(|tx| tx.order))
+        .await?;
+
+    let query = r#"
+        INSERT INTO txs
+            ("order", digest, version, data)
+        SELECT
+            *
+        FROM
+            UNNEST($1::BIGINT[], $2::BYTEA[], $3::TEXT[], $4::BYTEA[])
+        ON CONFLICT ("order") DO NOTHING"#;
+
+    let orders: Vec<_> = txs.iter().map(|tx| tx.order).collect();
+    let digests: Vec<_> = txs.iter().map(|tx| &tx.digest).collect();
+    let versions: Vec<_> = txs.iter().map(|tx| &tx.version).collect();
+    let data: Vec<_> = txs.iter().map(|tx| &tx.data).collect();
+
+    let inserted = db
+        .get()
+        .await?
+        .execute(query, &[&orders, &digests, &versions, &data])
+        .await
+        .context("Cannot insert missing txs")?;
+
+    Ok(inserted as usize)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn it_builds_insert_digest_query() {
-        assert_eq!(
-            &insert_digest_query(1),
-            "INSERT INTO txs (digest) VALUES (?) ON CONFLICT DO NOTHING",
+    /// The tables from the module docs, with `txs` not partitioned.
+    const SCHEMA: &str = r#"
+        CREATE TABLE digests (
+            id BIGSERIAL PRIMARY KEY,
+            digest BYTEA NOT NULL UNIQUE,
+            position BIGINT NOT NULL UNIQUE
+        );
+        CREATE TABLE digest_positions (next BIGINT NOT NULL);
+        INSERT INTO digest_positions VALUES (0);
+        CREATE TABLE digests_archive (
+            id BIGINT PRIMARY KEY,
+            digest BYTEA NOT NULL UNIQUE,
+            position BIGINT NOT NULL UNIQUE
+        );
+        CREATE TABLE txs (
+            "order" BIGINT NOT NULL,
+            digest BYTEA NOT NULL,
+            version TEXT NOT NULL,
+            data BYTEA NOT NULL,
+            PRIMARY KEY ("order")
         );
+        CREATE TABLE txs_detached (below_order BIGINT NOT NULL);"#;
+
+    /// Tests against Postgres are ignored by default. Run them with e.g.
+    /// `TEST_DB_CONN_CONF="host=localhost user=postgres" cargo test --
+    /// --ignored`. Each test gets a schema of its own, created anew.
+    async fn test_pool(schema: &str) -> Pool {
+        let conn_conf = std::env::var("TEST_DB_CONN_CONF")
+            .expect("TEST_DB_CONN_CONF must be set for db tests");
+
+        pool(&conn_conf, 1, &TlsConf::default())
+            .unwrap()
+            .get()
+            .await
+            .unwrap()
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0};",
+                schema
+            ))
+            .await
+            .unwrap();
+
+        let conn_conf =
+            format!("{} options='-c search_path={}'", conn_conf, schema);
+        let db = pool(&conn_conf, 2, &TlsConf::default()).unwrap();
+        db.get().await.unwrap().batch_execute(SCHEMA).await.unwrap();
+
+        db
+    }
+
+    fn d(n: u64) -> Digest {
+        let mut bytes = [0; Digest::LENGTH];
+        bytes[..8].copy_from_slice(&n.to_be_bytes());
+        Digest::new(bytes)
+    }
+
+    fn tx(order: i64) -> SuiTx {
+        SuiTx {
+            order,
+            digest: d(order as u64),
+            version: "test".to_string(),
+            data: vec![],
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn it_inserts_digests_in_order_skipping_stored() {
+        let storage = PgStorage::new(test_pool("test_insert_digests").await);
+
+        storage.insert_digests(&[d(1), d(2)]).await.unwrap();
+        storage
+            .insert_digests(&[d(3), d(2), d(4), d(3)])
+            .await
+            .unwrap();
 
         assert_eq!(
-            &insert_digest_query(2),
-            "INSERT INTO txs (digest) VALUES (?,?) ON CONFLICT DO NOTHING",
+            storage.select_digests_after_position(-1, 10).await.unwrap(),
+            vec![(0, d(1)), (1, d(2)), (2, d(3)), (3, d(4))]
         );
+        // ids are in the same order as positions
+        assert_eq!(
+            storage
+                .select_digests_since_inclusive(&d(1), 10)
+                .await
+                .unwrap(),
+            vec![d(1), d(2), d(3), d(4)]
+        );
+    }
 
+    #[tokio::test]
+    #[ignore]
+    async fn it_inserts_txs_skipping_stored_orders() {
+        let db = test_pool("test_insert_txs").await;
+        let storage = PgStorage::new(db.clone());
+
+        storage.insert_txs(&[tx(2), tx(1)]).await.unwrap();
+        assert!(storage.insert_txs(&[tx(3), tx(2)]).await.is_err());
+
+        // the failed insert didn't store tx 3 either
         assert_eq!(
-            &insert_digest_query(3),
-            "INSERT INTO txs (digest) VALUES (?,?,?) ON CONFLICT DO NOTHING",
+            insert_missing_txs(&db, &[tx(3), tx(2), tx(4)])
+                .await
+                .unwrap(),
+            2
         );
+        let orders: Vec<_> = select_txs_in_order_range(&db, 0, 10, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|tx| tx.order)
+            .collect();
+        assert_eq!(orders, [1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn it_copies_digests_in_order_across_chunks() {
+        let db = test_pool("test_copy_digests").await;
+
+        let n = COPY_DIGESTS_CHUNK as u64 + 10;
+        let digests: Vec<_> = (0..n).map(d).collect();
+        insert_digests(&db, &digests[10..20]).await.unwrap();
 
+        // one of the first chunk repeats in the second one
+        let mut batch = digests.clone();
+        batch.insert(COPY_DIGESTS_CHUNK + 5, digests[COPY_DIGESTS_CHUNK - 5]);
+        bulk_insert_digests(&db, &batch).await.unwrap();
+
+        let expected: Vec<_> = digests[10..20]
+            .iter()
+            .chain(&digests[..10])
+            .chain(&digests[20..])
+            .copied()
+            .enumerate()
+            .map(|(position, digest)| (position as i64, digest))
+            .collect();
         assert_eq!(
-            &insert_digest_query(4),
-            "INSERT INTO txs (digest) VALUES (?,?,?,?) ON CONFLICT DO NOTHING",
+            select_digests_after_position(&db, -1, n as usize + 1)
+                .await
+                .unwrap(),
+            expected
         );
     }
+
+    #[test]
+    fn it_finds_position_holes() {
+        assert!(position_holes(-1, &[]).is_empty());
+        assert!(position_holes(-1, &[0, 1, 2]).is_empty());
+        assert!(position_holes(4, &[5, 6]).is_empty());
+
+        assert_eq!(position_holes(-1, &[2, 3]), vec![0..=1]);
+        assert_eq!(position_holes(4, &[5, 7, 8, 12]), vec![6..=6, 9..=11]);
+    }
 }
@@ -0,0 +1,495 @@
+//! [`Storage`] which keeps everything in memory, for local development and
+//! tests. Nothing is persisted and each instance starts empty.
+//!
+//! Ids start at 1 as with a Postgres sequence, positions start at 0. Neither
+//! has gaps.
+
+use crate::queue::{Job, JobStatus};
+use crate::storage::Storage;
+use crate::SuiTx;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use misc::Digest;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Digest with id `n` is at index `n - 1`, its position is `n - 1`.
+    digests: Vec<Digest>,
+    ids: HashMap<Digest, i64>,
+    /// Job with id `n` is at index `n - 1`.
+    jobs: Vec<MemoryJob>,
+    /// How many digests, in order of position, have a job, by kind.
+    enqueue_cursors: HashMap<String, usize>,
+    watermarks: HashMap<String, i64>,
+    txs: BTreeMap<i64, SuiTx>,
+}
+
+struct MemoryJob {
+    kind: String,
+    digest_id: i64,
+    status: JobStatus,
+    attempts: i32,
+    locked_by: Option<String>,
+    locked_until: Option<Instant>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // the lock is never held across a panic in this module
+        self.state.lock().unwrap()
+    }
+}
+
+impl State {
+    fn digests_since(
+        &self,
+        digest: &Digest,
+        skip: usize,
+        limit: usize,
+    ) -> Vec<Digest> {
+        match self.ids.get(digest) {
+            Some(id) => self
+                .digests
+                .iter()
+                .skip(*id as usize - 1 + skip)
+                .take(limit)
+                .copied()
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn job_leased_to(
+        &mut self,
+        id: i64,
+        worker_id: &str,
+    ) -> Option<&mut MemoryJob> {
+        let now = Instant::now();
+        self.jobs.get_mut((id - 1) as usize).filter(|job| {
+            job.locked_by.as_deref() == Some(worker_id)
+                && job.status == JobStatus::Pending
+                && job.locked_until.map_or(false, |until| until > now)
+        })
+    }
+
+    fn complete(&mut self, worker_id: &str, ids: &[i64]) -> Vec<i64> {
+        let mut completed = vec![];
+        for id in ids {
+            if let Some(job) = self.job_leased_to(*id, worker_id) {
+                job.status = JobStatus::Done;
+                job.locked_until = None;
+                completed.push(*id);
+            }
+        }
+
+        completed
+    }
+
+    fn fail(&mut self, worker_id: &str, ids: &[i64], max_attempts: i32) {
+        for id in ids {
+            if let Some(job) = self.job_leased_to(*id, worker_id) {
+                job.status = if job.attempts >= max_attempts {
+                    JobStatus::Failed
+                } else {
+                    JobStatus::Pending
+                };
+                job.locked_until = None;
+            }
+        }
+    }
+
+    fn insert_txs(&mut self, txs: Vec<SuiTx>) -> Result<()> {
+        // all or nothing, as with a single insert statement
+        for tx in &txs {
+            if self.txs.contains_key(&tx.order) {
+                bail!("Tx of order {} is stored already", tx.order);
+            }
+        }
+        for tx in txs {
+            self.txs.insert(tx.order, tx);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn insert_digests(&self, digests: &[Digest]) -> Result<()> {
+        let mut state = self.state();
+        for digest in digests {
+            if !state.ids.contains_key(digest) {
+                state.digests.push(*digest);
+                let id = state.digests.len() as i64;
+                state.ids.insert(*digest, id);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn select_digests_since_exclusive(
+        &self,
+        digest: &Digest,
+        limit: usize,
+    ) -> Result<Vec<Digest>> {
+        Ok(self.state().digests_since(digest, 1, limit))
+    }
+
+    async fn select_digests_since_inclusive(
+        &self,
+        digest: &Digest,
+        limit: usize,
+    ) -> Result<Vec<Digest>> {
+        Ok(self.state().digests_since(digest, 0, limit))
+    }
+
+    async fn select_digests_after_position(
+        &self,
+        position: i64,
+        limit: usize,
+    ) -> Result<Vec<(i64, Digest)>> {
+        let from = (position + 1).max(0);
+
+        Ok(self
+            .state()
+            .digests
+            .iter()
+            .enumerate()
+            .skip(from as usize)
+            .take(limit)
+            .map(|(position, digest)| (position as i64, *digest))
+            .collect())
+    }
+
+    async fn has_digest(&self, digest: &Digest) -> Result<bool> {
+        Ok(self.state().ids.contains_key(digest))
+    }
+
+    async fn select_stored_digests(
+        &self,
+        digests: &[Digest],
+    ) -> Result<Vec<(i64, Digest)>> {
+        let state = self.state();
+        let mut stored: Vec<_> = digests
+            .iter()
+            .filter_map(|digest| Some((*state.ids.get(digest)?, *digest)))
+            .collect();
+        stored.sort_unstable();
+        stored.dedup();
+
+        Ok(stored)
+    }
+
+    async fn enqueue_from_digests(
+        &self,
+        kind: &str,
+        limit: i64,
+    ) -> Result<u64> {
+        let mut state = self.state();
+        let state = &mut *state;
+
+        let cursor = state.enqueue_cursors.entry(kind.to_string()).or_default();
+        let until = state
+            .digests
+            .len()
+            .min(cursor.saturating_add(limit.max(0) as usize));
+        for index in *cursor..until {
+            state.jobs.push(MemoryJob {
+                kind: kind.to_string(),
+                digest_id: index as i64 + 1,
+                status: JobStatus::Pending,
+                attempts: 0,
+                locked_by: None,
+                locked_until: None,
+            });
+        }
+        let created = until - *cursor;
+        *cursor = until;
+
+        Ok(created as u64)
+    }
+
+    async fn claim(
+        &self,
+        kind: &str,
+        worker_id: &str,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<Job>> {
+        let mut state = self.state();
+        let state = &mut *state;
+
+        let now = Instant::now();
+        let mut claimed = vec![];
+        for (index, job) in state.jobs.iter_mut().enumerate() {
+            if claimed.len() as i64 >= limit {
+                break;
+            }
+            let is_claimable = job.kind == kind
+                && job.status == JobStatus::Pending
+                && job.locked_until.map_or(true, |until| until < now);
+            if !is_claimable {
+                continue;
+            }
+
+            job.attempts += 1;
+            job.locked_by = Some(worker_id.to_string());
+            job.locked_until = Some(now + lease);
+            claimed.push(Job {
+                id: index as i64 + 1,
+                digest_id: job.digest_id,
+                digest: state.digests[job.digest_id as usize - 1],
+                attempts: job.attempts,
+            });
+        }
+
+        Ok(claimed)
+    }
+
+    async fn complete(&self, worker_id: &str, ids: &[i64]) -> Result<Vec<i64>> {
+        Ok(self.state().complete(worker_id, ids))
+    }
+
+    async fn fail(
+        &self,
+        worker_id: &str,
+        ids: &[i64],
+        max_attempts: i32,
+    ) -> Result<()> {
+        self.state().fail(worker_id, ids, max_attempts);
+
+        Ok(())
+    }
+
+    async fn fail_abandoned(
+        &self,
+        kind: &str,
+        max_attempts: i32,
+    ) -> Result<u64> {
+        let now = Instant::now();
+        let mut failed = 0;
+        for job in &mut self.state().jobs {
+            let is_abandoned = job.kind == kind
+                && job.status == JobStatus::Pending
+                && job.locked_until.map_or(false, |until| until < now)
+                && job.attempts >= max_attempts;
+            if is_abandoned {
+                job.status = JobStatus::Failed;
+                job.locked_until = None;
+                failed += 1;
+            }
+        }
+
+        Ok(failed)
+    }
+
+    async fn finish_jobs(
+        &self,
+        worker_id: &str,
+        completed: &[i64],
+        failed: &[i64],
+        max_attempts: i32,
+        mut txs: HashMap<i64, SuiTx>,
+    ) -> Result<Vec<i64>> {
+        let mut state = self.state();
+
+        // nothing changes if the txs cannot be inserted
+        let leased: Vec<_> = completed
+            .iter()
+            .copied()
+            .filter(|id| state.job_leased_to(*id, worker_id).is_some())
+            .collect();
+        state.insert_txs(
+            leased.iter().filter_map(|id| txs.remove(id)).collect(),
+        )?;
+        let completed = state.complete(worker_id, &leased);
+        state.fail(worker_id, failed, max_attempts);
+
+        Ok(completed)
+    }
+
+    async fn advance_watermark(&self, kind: &str) -> Result<Option<i64>> {
+        let mut state = self.state();
+        let state = &mut *state;
+
+        let lowest_pending = state
+            .jobs
+            .iter()
+            .filter(|job| job.kind == kind && job.status == JobStatus::Pending)
+            .map(|job| job.digest_id)
+            .min();
+        // digests are enqueued in order, the first one without a job is right
+        // after the cursor
+        let cursor = state.enqueue_cursors.get(kind).copied().unwrap_or(0);
+        let after_enqueued =
+            (!state.digests.is_empty()).then(|| cursor as i64 + 1);
+
+        let until = match [lowest_pending, after_enqueued]
+            .into_iter()
+            .flatten()
+            .min()
+        {
+            Some(digest_id) => digest_id - 1,
+            None => return Ok(None),
+        };
+        let watermark =
+            state.watermarks.entry(kind.to_string()).or_insert(until);
+        *watermark = (*watermark).max(until);
+
+        Ok(Some(*watermark))
+    }
+
+    async fn select_watermark(&self, kind: &str) -> Result<Option<i64>> {
+        Ok(self.state().watermarks.get(kind).copied())
+    }
+
+    async fn insert_txs(&self, txs: &[SuiTx]) -> Result<()> {
+        self.state().insert_txs(txs.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn d(byte: u8) -> Digest {
+        Digest::new([byte; Digest::LENGTH])
+    }
+
+    #[tokio::test]
+    async fn it_skips_stored_digests() {
+        let storage = MemoryStorage::new();
+        storage.insert_digests(&[d(1), d(2)]).await.unwrap();
+        storage.insert_digests(&[d(2), d(3), d(3)]).await.unwrap();
+
+        assert_eq!(
+            storage.select_digests_after_position(-1, 10).await.unwrap(),
+            vec![(0, d(1)), (1, d(2)), (2, d(3))]
+        );
+        assert_eq!(
+            storage
+                .select_digests_since_exclusive(&d(1), 1)
+                .await
+                .unwrap(),
+            vec![d(2)]
+        );
+        assert_eq!(
+            storage
+                .select_digests_since_inclusive(&d(2), 10)
+                .await
+                .unwrap(),
+            vec![d(2), d(3)]
+        );
+        assert!(storage
+            .select_digests_since_inclusive(&d(4), 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage.has_digest(&d(3)).await.unwrap());
+        assert!(!storage.has_digest(&d(4)).await.unwrap());
+        assert_eq!(
+            storage
+                .select_stored_digests(&[d(4), d(3), d(1)])
+                .await
+                .unwrap(),
+            vec![(1, d(1)), (3, d(3))]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_leases_jobs() {
+        let storage = MemoryStorage::new();
+        storage.insert_digests(&[d(1), d(2), d(3)]).await.unwrap();
+        assert_eq!(storage.enqueue_from_digests("k", 2).await.unwrap(), 2);
+        assert_eq!(storage.enqueue_from_digests("k", 2).await.unwrap(), 1);
+
+        let lease = Duration::from_secs(60);
+        let a = storage.claim("k", "a", 2, lease).await.unwrap();
+        let b = storage.claim("k", "b", 2, lease).await.unwrap();
+        assert_eq!(
+            a.iter().map(|job| job.digest_id).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(b.iter().map(|job| job.digest).collect::<Vec<_>>(), [d(3)]);
+
+        // b doesn't hold the lease of a's jobs
+        assert_eq!(storage.complete("b", &[1, 3]).await.unwrap(), [3]);
+        assert_eq!(storage.complete("a", &[1]).await.unwrap(), [1]);
+
+        // a still holds the lease of its other job
+        assert!(storage.claim("k", "c", 10, lease).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_reclaims_expired_leases() {
+        let storage = MemoryStorage::new();
+        storage.insert_digests(&[d(1)]).await.unwrap();
+        storage.enqueue_from_digests("k", 10).await.unwrap();
+
+        let expired = storage.claim("k", "a", 1, Duration::ZERO).await.unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        let reclaimed = storage
+            .claim("k", "b", 1, Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        assert_eq!(reclaimed.len(), 1);
+        assert_eq!(reclaimed[0].attempts, 2);
+        assert!(storage
+            .complete("a", &[expired[0].id])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_finishes_jobs_and_advances_watermark() {
+        let storage = MemoryStorage::new();
+        storage.insert_digests(&[d(1), d(2), d(3)]).await.unwrap();
+        assert_eq!(storage.advance_watermark("k").await.unwrap(), Some(0));
+        storage.enqueue_from_digests("k", 10).await.unwrap();
+
+        let lease = Duration::from_secs(60);
+        let jobs = storage.claim("k", "a", 3, lease).await.unwrap();
+        let tx = |order| SuiTx {
+            order,
+            digest: d(order as u8),
+            version: String::new(),
+            data: vec![],
+        };
+        let txs = HashMap::from([(jobs[0].id, tx(1)), (jobs[2].id, tx(3))]);
+        let completed = storage
+            .finish_jobs("a", &[jobs[0].id, jobs[2].id], &[jobs[1].id], 1, txs)
+            .await
+            .unwrap();
+        assert_eq!(completed, [jobs[0].id, jobs[2].id]);
+
+        // the second job ran out of attempts, hence all are finished
+        assert_eq!(storage.advance_watermark("k").await.unwrap(), Some(3));
+        assert_eq!(storage.select_watermark("k").await.unwrap(), Some(3));
+
+        // a tx of the same order is stored already
+        storage.insert_digests(&[d(4)]).await.unwrap();
+        storage.enqueue_from_digests("k", 10).await.unwrap();
+        let jobs = storage.claim("k", "a", 1, lease).await.unwrap();
+        let txs = HashMap::from([(jobs[0].id, tx(3))]);
+        assert!(storage
+            .finish_jobs("a", &[jobs[0].id], &[], 1, txs)
+            .await
+            .is_err());
+        assert_eq!(storage.advance_watermark("k").await.unwrap(), Some(3));
+    }
+}
@@ -1,7 +1,7 @@
 use misc::Digest;
 use postgres_types::{FromSql, ToSql};
 
-#[derive(Debug, ToSql, FromSql)]
+#[derive(Clone, Debug, ToSql, FromSql)]
 pub struct SuiTx {
     /// Maps to `id` in `digests` table. Not a foreign key so that we can empty
     /// the `digests` table but keep the txs.
@@ -0,0 +1,69 @@
+//! Relays publish digests to other systems in order of their position, see
+//! the ordering section of the crate docs. Each relay commits the position up
+//! to which it has published, so that another instance can take over where it
+//! left off.
+//!
+//! ```sql
+//! CREATE TABLE relay_offsets (
+//!     name TEXT PRIMARY KEY,
+//!     -- digests up to this position inclusive are published
+//!     position BIGINT NOT NULL
+//! );
+//! ```
+//!
+//! Only one instance of a relay should publish at a time, the others wait on
+//! [`try_lock`]. Digests which a relay hasn't published yet are never
+//! archived, see [`crate::archive`].
+
+use crate::GenericDbClient;
+use anyhow::{Context, Result};
+
+/// Tries to take a session lock for the relay of given name. The lock is held
+/// until the connection closes, so the caller keeps the connection for as long
+/// as it's relaying.
+pub async fn try_lock(db: &impl GenericDbClient, name: &str) -> Result<bool> {
+    let row = db
+        .query_one("SELECT pg_try_advisory_lock(hashtext($1))", &[&name])
+        .await
+        .with_context(|| format!("Cannot lock relay '{}'", name))?;
+
+    Ok(row.try_get(0)?)
+}
+
+/// Returns [`None`] if the relay hasn't committed anything yet.
+pub async fn select(
+    db: &impl GenericDbClient,
+    name: &str,
+) -> Result<Option<i64>> {
+    let row = db
+        .query_opt(
+            "SELECT position FROM relay_offsets WHERE name = $1",
+            &[&name],
+        )
+        .await
+        .with_context(|| format!("Cannot select offset of relay '{}'", name))?;
+
+    Ok(row.map(|row| row.try_get("position")).transpose()?)
+}
+
+/// The offset never moves backwards.
+pub async fn commit(
+    db: &impl GenericDbClient,
+    name: &str,
+    position: i64,
+) -> Result<()> {
+    let query = "
+        INSERT INTO relay_offsets
+            (name, position)
+        VALUES
+            ($1, $2)
+        ON CONFLICT (name) DO UPDATE SET
+            position = GREATEST(relay_offsets.position, EXCLUDED.position)";
+
+    let statement = db.prepare_cached(query).await?;
+    db.execute(&statement, &[&name, &position])
+        .await
+        .with_context(|| format!("Cannot commit offset of relay '{}'", name))?;
+
+    Ok(())
+}
@@ -0,0 +1,284 @@
+//! The `txs` table is partitioned by ranges of `"order"`, so that txs of old
+//! digests can be detached or dropped cheaply instead of deleted row by row.
+//!
+//! ```sql
+//! CREATE TABLE txs (
+//!     "order" BIGINT NOT NULL,
+//!     digest BYTEA NOT NULL,
+//!     version TEXT NOT NULL,
+//!     data BYTEA NOT NULL,
+//!     PRIMARY KEY ("order")
+//! ) PARTITION BY RANGE ("order");
+//!
+//! -- one row per detaching, see `detach_txs_partitions_below`
+//! CREATE TABLE txs_detached (below_order BIGINT NOT NULL);
+//! ```
+//!
+//! Each partition holds [`TXS_PARTITION_SIZE`] orders and is named by its
+//! index, e.g. `txs_p0` holds orders `0..1_000_000`. Partitions are created on
+//! demand with [`create_txs_partitions`] before inserting. Queries on `txs`
+//! don't need to know about partitions.
+//!
+//! # Migration
+//!
+//! While `txs` is a plain table, no partitions are created and inserts work as
+//! before. To partition an existing table, stop the pullers and run
+//!
+//! ```sql
+//! BEGIN;
+//! ALTER TABLE txs RENAME TO txs_unpartitioned;
+//! ALTER INDEX txs_pkey RENAME TO txs_unpartitioned_pkey;
+//! CREATE TABLE txs ( ... ) PARTITION BY RANGE ("order");
+//! CREATE TABLE txs_detached (below_order BIGINT NOT NULL);
+//! DO $$
+//! BEGIN
+//!     FOR i IN 0..(
+//!         SELECT COALESCE(MAX("order"), -1) / 1000000 FROM txs_unpartitioned
+//!     ) LOOP
+//!         EXECUTE format(
+//!             'CREATE TABLE txs_p%s PARTITION OF txs '
+//!             'FOR VALUES FROM (%s) TO (%s)',
+//!             i, i * 1000000, (i + 1) * 1000000
+//!         );
+//!     END LOOP;
+//! END $$;
+//! INSERT INTO txs SELECT * FROM txs_unpartitioned;
+//! DROP TABLE txs_unpartitioned;
+//! COMMIT;
+//! ```
+
+use crate::{GenericDbClient, Pool};
+use anyhow::{bail, Context, Result};
+use log::warn;
+use std::collections::{BTreeSet, HashSet};
+
+/// How many orders each partition of `txs` holds. Changing this requires
+/// repartitioning the table.
+pub const TXS_PARTITION_SIZE: i64 = 1_000_000;
+
+/// Advisory lock key which serializes creating partitions across services.
+const CREATE_PARTITIONS_LOCK: i64 = 0x7478_735f_7061_7274;
+
+/// Makes sure that there's a partition for each of given orders. Call this
+/// before inserting txs, outside of the inserting transaction, as creating a
+/// partition locks the whole `txs` table.
+///
+/// Does nothing if `txs` is not partitioned, see the migration in the module
+/// docs. Fails if some of the orders are in a range which has been detached,
+/// since their txs are not meant to be stored anymore.
+pub async fn create_txs_partitions(
+    db: &Pool,
+    orders: impl IntoIterator<Item = i64>,
+) -> Result<()> {
+    let indexes: BTreeSet<_> = orders.into_iter().map(partition_of).collect();
+    if indexes.is_empty() {
+        return Ok(());
+    }
+
+    let mut client = db.get().await?;
+
+    let statement = client
+        .prepare_cached(
+            "
+            SELECT EXISTS (
+                SELECT 1 FROM pg_partitioned_table
+                WHERE partrelid = 'txs'::regclass
+            )",
+        )
+        .await?;
+    let is_partitioned: bool = client.query_one(&statement, &[]).await?.get(0);
+    if !is_partitioned {
+        return Ok(());
+    }
+
+    // usually all partitions exist already, checking is cheap
+    let missing = missing_partitions(&**client, &indexes).await?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let tx = client.transaction().await?;
+    tx.execute(
+        "SELECT pg_advisory_xact_lock($1)",
+        &[&CREATE_PARTITIONS_LOCK],
+    )
+    .await?;
+    // another service might have created them while we waited for the lock
+    for index in missing_partitions(&tx, &indexes).await? {
+        let (from, to) = partition_bounds(index);
+        // the name is ours, not user input
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {} PARTITION OF txs \
+            FOR VALUES FROM ({}) TO ({})",
+            partition_name(index),
+            from,
+            to
+        );
+        tx.batch_execute(&query).await.with_context(|| {
+            format!("Cannot create txs partition for orders {}..{}", from, to)
+        })?;
+    }
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Returns those of given partition indexes which are not attached to `txs`.
+/// Fails if any of them is below a detached range.
+async fn missing_partitions(
+    db: &impl GenericDbClient,
+    indexes: &BTreeSet<i64>,
+) -> Result<Vec<i64>> {
+    let query = "
+        SELECT
+            (SELECT MAX(below_order) FROM txs_detached) AS detached_below,
+            ARRAY(
+                SELECT
+                    c.relname::TEXT
+                FROM
+                    pg_inherits i
+                    JOIN pg_class c ON c.oid = i.inhrelid
+                WHERE
+                    i.inhparent = 'txs'::regclass
+            ) AS attached";
+
+    let statement = db.prepare_cached(query).await?;
+    let row = db
+        .query_one(&statement, &[])
+        .await
+        .context("Cannot select txs partitions")?;
+    let detached_below: Option<i64> = row.try_get("detached_below")?;
+    let attached: HashSet<String> = row
+        .try_get::<_, Vec<String>>("attached")?
+        .into_iter()
+        .collect();
+
+    let mut missing = vec![];
+    for &index in indexes {
+        if attached.contains(&partition_name(index)) {
+            continue;
+        }
+
+        let (from, to) = partition_bounds(index);
+        if let Some(detached_below) = detached_below {
+            if from < detached_below {
+                bail!(
+                    "Txs with orders {}..{} were detached, \
+                    refusing to create their partition again",
+                    from,
+                    to
+                );
+            }
+        }
+        missing.push(index);
+    }
+
+    Ok(missing)
+}
+
+/// Detaches partitions of `txs` which only hold orders lower than given one,
+/// and drops them unless `keep` is set. Returns names of the partitions.
+///
+/// Detached partitions are renamed to e.g. `txs_p3_detached`, they're plain
+/// tables which can be dumped and dropped later. Partitions for orders below
+/// the detached ones are never created again.
+pub async fn detach_txs_partitions_below(
+    db: &Pool,
+    order: i64,
+    keep: bool,
+) -> Result<Vec<String>> {
+    let mut client = db.get().await?;
+
+    let query = "
+        SELECT
+            c.relname
+        FROM
+            pg_inherits i
+            JOIN pg_class c ON c.oid = i.inhrelid
+        WHERE
+            i.inhparent = 'txs'::regclass";
+    let mut indexes = vec![];
+    for row in client.query(query, &[]).await? {
+        let name: String = row.try_get(0)?;
+        match parse_partition_name(&name) {
+            Some(index) if partition_bounds(index).1 <= order => {
+                indexes.push(index)
+            }
+            Some(_) => (),
+            None => warn!("Unexpected partition '{}' of txs", name),
+        }
+    }
+    indexes.sort_unstable();
+
+    let mut detached = Vec::with_capacity(indexes.len());
+    for index in indexes {
+        let name = partition_name(index);
+        let tx = client.transaction().await?;
+        tx.execute(
+            "INSERT INTO txs_detached (below_order) VALUES ($1)",
+            &[&partition_bounds(index).1],
+        )
+        .await
+        .context("Cannot record detached txs")?;
+        tx.batch_execute(&format!("ALTER TABLE txs DETACH PARTITION {}", name))
+            .await
+            .with_context(|| format!("Cannot detach partition '{}'", name))?;
+        let name = if keep {
+            let detached_name = format!("{}_detached", name);
+            tx.batch_execute(&format!(
+                "ALTER TABLE {} RENAME TO {}",
+                name, detached_name
+            ))
+            .await
+            .with_context(|| format!("Cannot rename partition '{}'", name))?;
+            detached_name
+        } else {
+            tx.batch_execute(&format!("DROP TABLE {}", name))
+                .await
+                .with_context(|| format!("Cannot drop partition '{}'", name))?;
+            name
+        };
+        tx.commit().await?;
+
+        detached.push(name);
+    }
+
+    Ok(detached)
+}
+
+fn partition_of(order: i64) -> i64 {
+    order.div_euclid(TXS_PARTITION_SIZE)
+}
+
+/// Orders from inclusive, to exclusive.
+fn partition_bounds(index: i64) -> (i64, i64) {
+    (index * TXS_PARTITION_SIZE, (index + 1) * TXS_PARTITION_SIZE)
+}
+
+fn partition_name(index: i64) -> String {
+    format!("txs_p{}", index)
+}
+
+fn parse_partition_name(name: &str) -> Option<i64> {
+    name.strip_prefix("txs_p")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_maps_orders_to_partitions() {
+        assert_eq!(partition_of(0), 0);
+        assert_eq!(partition_of(TXS_PARTITION_SIZE - 1), 0);
+        assert_eq!(partition_of(TXS_PARTITION_SIZE), 1);
+
+        let (from, to) = partition_bounds(partition_of(2_500_000));
+        assert!(from <= 2_500_000 && 2_500_000 < to);
+
+        assert_eq!(parse_partition_name(&partition_name(12)), Some(12));
+        assert_eq!(parse_partition_name("txs_default"), None);
+        assert_eq!(parse_partition_name("txs_p12_detached"), None);
+        assert_eq!(parse_partition_name("digests"), None);
+    }
+}
@@ -0,0 +1,438 @@
+//! Postgres can be used to an extent as a job queue. Each consumer of digests,
+//! e.g. the puller, processes them as jobs of its own kind, so that several
+//! independent consumers can track their progress over the same digests
+//! without touching the `digests` table.
+//!
+//! ```sql
+//! CREATE TABLE jobs (
+//!     id BIGSERIAL PRIMARY KEY,
+//!     kind TEXT NOT NULL,
+//!     -- maps to `id` in `digests` table
+//!     digest_id BIGINT NOT NULL,
+//!     digest BYTEA NOT NULL,
+//!     -- see `JobStatus`
+//!     status SMALLINT NOT NULL DEFAULT 0,
+//!     attempts INT NOT NULL DEFAULT 0,
+//!     -- the worker which leased the job last
+//!     locked_by TEXT,
+//!     -- set while the job is leased
+//!     locked_until TIMESTAMPTZ,
+//!     UNIQUE (kind, digest_id)
+//! );
+//! CREATE INDEX jobs_pending_idx ON jobs (kind, id) WHERE status = 0;
+//!
+//! CREATE TABLE enqueue_cursors (
+//!     kind TEXT PRIMARY KEY,
+//!     -- digests up to this position inclusive have a job of this kind
+//!     position BIGINT NOT NULL
+//! );
+//!
+//! CREATE TABLE watermarks (
+//!     kind TEXT PRIMARY KEY,
+//!     -- jobs of this kind with digest id up to this one inclusive are
+//!     -- finished
+//!     digest_id BIGINT NOT NULL
+//! );
+//! ```
+//!
+//! To add the cursors next to existing jobs, run
+//!
+//! ```sql
+//! INSERT INTO enqueue_cursors (kind, position)
+//!     SELECT j.kind, MAX(d.position)
+//!     FROM jobs j JOIN digests d ON d.id = j.digest_id
+//!     GROUP BY j.kind;
+//! ```
+//!
+//! The puller used to track its progress in `digests.status`, 0 for pending
+//! and 1 for pulled. To move it to the `pull_txs` jobs, stop the pullers and
+//! run in one transaction
+//!
+//! ```sql
+//! INSERT INTO jobs (kind, digest_id, digest, status)
+//!     SELECT 'pull_txs', id, digest, CASE status WHEN 1 THEN 1 ELSE 0 END
+//!     FROM digests;
+//!
+//! INSERT INTO enqueue_cursors (kind, position)
+//!     SELECT 'pull_txs', MAX(position) FROM digests
+//!     HAVING COUNT(*) > 0;
+//!
+//! INSERT INTO watermarks (kind, digest_id)
+//!     SELECT 'pull_txs', COALESCE(
+//!         (SELECT MIN(digest_id) - 1 FROM jobs
+//!             WHERE kind = 'pull_txs' AND status = 0),
+//!         (SELECT MAX(id) FROM digests)
+//!     )
+//!     WHERE EXISTS (SELECT 1 FROM digests);
+//!
+//! ALTER TABLE digests DROP COLUMN status;
+//! ```
+//!
+//! Otherwise the first enqueue creates a pending job for every digest and
+//! the puller fetches all txs again.
+//!
+//! A worker claims jobs by leasing them for a while in a short statement, so
+//! that no transaction is open while the worker processes them. Once the lease
+//! expires, e.g. because the worker died or is too slow, the job is up for
+//! grabs again. A worker reports outcome only of the jobs it still holds the
+//! lease of.
+
+use crate::{GenericDbClient, Pool};
+use anyhow::{Context, Result};
+use misc::Digest;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i16)]
+pub enum JobStatus {
+    Pending = 0,
+    Done = 1,
+    /// Ran out of attempts.
+    Failed = 2,
+}
+
+#[derive(Debug)]
+pub struct Job {
+    pub id: i64,
+    pub digest_id: i64,
+    pub digest: Digest,
+    /// Including the current one.
+    pub attempts: i32,
+}
+
+/// Creates a pending job of given kind for each of the next `limit` digests
+/// after the kind's enqueue cursor, in order of digest positions, and moves
+/// the cursor past them. Returns how many jobs were created.
+///
+/// Positions are committed in ascending order, see [`crate`], so no digest
+/// is ever committed behind the cursor. Concurrent callers might select the
+/// same digests, the second one then creates no jobs for them.
+pub async fn enqueue_from_digests(
+    db: &Pool,
+    kind: &str,
+    limit: i64,
+) -> Result<u64> {
+    let query = "
+        WITH next_digests AS (
+            SELECT
+                id, digest, position
+            FROM
+                digests
+            WHERE
+                position > COALESCE(
+                    (SELECT position FROM enqueue_cursors WHERE kind = $1),
+                    -1
+                )
+            ORDER BY
+                position ASC
+            LIMIT $2
+        ),
+        enqueued AS (
+            INSERT INTO jobs
+                (kind, digest_id, digest)
+            SELECT
+                $1, id, digest
+            FROM
+                next_digests
+            ORDER BY
+                position ASC
+            ON CONFLICT (kind, digest_id) DO NOTHING
+            RETURNING
+                1
+        ),
+        cursor AS (
+            INSERT INTO enqueue_cursors
+                (kind, position)
+            SELECT
+                $1, MAX(position)
+            FROM
+                next_digests
+            HAVING
+                COUNT(*) > 0
+            ON CONFLICT (kind) DO UPDATE SET
+                position = GREATEST(
+                    enqueue_cursors.position, EXCLUDED.position
+                )
+        )
+        SELECT COUNT(*) AS enqueued FROM enqueued";
+
+    let client = db.get().await?;
+    let statement = client.prepare_cached(query).await?;
+    let enqueued: i64 = client
+        .query_one(&statement, &[&kind, &limit])
+        .await
+        .with_context(|| format!("Cannot enqueue '{}' jobs", kind))?
+        .try_get("enqueued")?;
+
+    Ok(enqueued as u64)
+}
+
+/// Leases up to `limit` pending jobs of given kind to the worker for given
+/// duration. Jobs whose lease has expired are claimed again, jobs leased by
+/// another worker are skipped.
+///
+/// This is a single statement, it doesn't need a transaction.
+pub async fn claim(
+    db: &Pool,
+    kind: &str,
+    worker_id: &str,
+    limit: i64,
+    lease: Duration,
+) -> Result<Vec<Job>> {
+    // pending status is a literal so that the partial index applies
+    let query = "
+        UPDATE
+            jobs
+        SET
+            attempts = attempts + 1,
+            locked_by = $2,
+            locked_until = now() + make_interval(secs => $4)
+        WHERE
+            id IN (
+                SELECT
+                    id
+                FROM
+                    jobs
+                WHERE
+                    kind = $1
+                    AND status = 0
+                    AND (locked_until IS NULL OR locked_until < now())
+                ORDER BY
+                    id ASC
+                LIMIT $3 FOR UPDATE SKIP LOCKED
+            )
+        RETURNING
+            id, digest_id, digest, attempts";
+
+    let client = db.get().await?;
+    let statement = client.prepare_cached(query).await?;
+    let rows = client
+        .query(
+            &statement,
+            &[&kind, &worker_id, &limit, &lease.as_secs_f64()],
+        )
+        .await
+        .with_context(|| format!("Cannot claim '{}' jobs", kind))?;
+
+    rows.into_iter().map(job_from_row).collect()
+}
+
+/// Given job ids, sets status of those which are still leased to the worker to
+/// [`JobStatus::Done`] and returns their ids.
+///
+/// A job whose lease expired might have been claimed by another worker, in
+/// which case its outcome is up to that worker. Hence jobs which are no longer
+/// pending or whose lease expired are skipped, even if nobody claimed them
+/// yet. Call this in the same transaction as writing the outcome of the
+/// returned jobs, so that the lease cannot be taken over in between.
+pub async fn complete(
+    db: &impl GenericDbClient,
+    worker_id: &str,
+    ids: &[i64],
+) -> Result<Vec<i64>> {
+    let query = "
+        UPDATE
+            jobs
+        SET
+            status = $3, locked_until = NULL
+        WHERE
+            id = ANY($1)
+            AND locked_by = $2
+            AND status = 0
+            AND locked_until > now()
+        RETURNING
+            id";
+
+    let statement = db.prepare_cached(query).await?;
+    let rows = db
+        .query(&statement, &[&ids, &worker_id, &(JobStatus::Done as i16)])
+        .await
+        .context("Cannot complete jobs")?;
+
+    rows.into_iter().map(|row| Ok(row.try_get("id")?)).collect()
+}
+
+/// Given job ids, releases those which are still leased to the worker back to
+/// the queue unless they've been attempted `max_attempts` times already, in
+/// which case they're [`JobStatus::Failed`]. As with [`complete`], jobs whose
+/// lease expired are skipped.
+pub async fn fail(
+    db: &impl GenericDbClient,
+    worker_id: &str,
+    ids: &[i64],
+    max_attempts: i32,
+) -> Result<()> {
+    let query = "
+        UPDATE
+            jobs
+        SET
+            status = CASE
+                WHEN attempts >= $3 THEN $4::SMALLINT
+                ELSE $5::SMALLINT
+            END,
+            locked_until = NULL
+        WHERE
+            id = ANY($1)
+            AND locked_by = $2
+            AND status = 0
+            AND locked_until > now()";
+
+    let statement = db.prepare_cached(query).await?;
+    db.execute(
+        &statement,
+        &[
+            &ids,
+            &worker_id,
+            &max_attempts,
+            &(JobStatus::Failed as i16),
+            &(JobStatus::Pending as i16),
+        ],
+    )
+    .await
+    .context("Cannot fail jobs")?;
+
+    Ok(())
+}
+
+/// Workers which die mid-job never report back and the job is claimed again
+/// once its lease expires. Jobs which were abandoned `max_attempts` times are
+/// set to [`JobStatus::Failed`] instead, so that a job which e.g. crashes its
+/// workers isn't claimed forever. Returns how many jobs failed.
+pub async fn fail_abandoned(
+    db: &Pool,
+    kind: &str,
+    max_attempts: i32,
+) -> Result<u64> {
+    let query = "
+        UPDATE
+            jobs
+        SET
+            status = $3, locked_until = NULL
+        WHERE
+            kind = $1
+            AND status = 0
+            AND locked_until < now()
+            AND attempts >= $2";
+
+    db.get()
+        .await?
+        .execute(query, &[&kind, &max_attempts, &(JobStatus::Failed as i16)])
+        .await
+        .with_context(|| format!("Cannot fail abandoned '{}' jobs", kind))
+}
+
+/// Parallel workers finish jobs in arbitrary order. The watermark of a kind is
+/// the digest id up to which, inclusive, all jobs of that kind are finished,
+/// i.e. they're either done or failed. Consumers of the workers' output, e.g.
+/// of `txs`, can safely read by digest id up to the watermark.
+///
+/// Sets the watermark as far as it got and returns it. It never moves
+/// backwards. Returns [`None`] if there are no digests yet.
+pub async fn advance_watermark(db: &Pool, kind: &str) -> Result<Option<i64>> {
+    // the watermark stops right before the lowest digest which either has a
+    // pending job or doesn't have a job yet, i.e. the first one after the
+    // enqueue cursor as ids and positions are assigned in the same order
+    let query = "
+        INSERT INTO watermarks
+            (kind, digest_id)
+        SELECT
+            $1, w.digest_id
+        FROM (
+            SELECT LEAST(
+                (
+                    SELECT MIN(digest_id) FROM jobs
+                    WHERE kind = $1 AND status = 0
+                ),
+                (
+                    SELECT id FROM digests
+                    WHERE position > COALESCE(
+                        (SELECT position FROM enqueue_cursors WHERE kind = $1),
+                        -1
+                    )
+                    ORDER BY position ASC
+                    LIMIT 1
+                ),
+                (SELECT MAX(digest_id) + 1 FROM jobs WHERE kind = $1)
+            ) - 1 AS digest_id
+        ) w
+        WHERE
+            w.digest_id IS NOT NULL
+        ON CONFLICT (kind) DO UPDATE SET
+            digest_id = GREATEST(watermarks.digest_id, EXCLUDED.digest_id)
+        RETURNING
+            digest_id";
+
+    let row = db
+        .get()
+        .await?
+        .query_opt(query, &[&kind])
+        .await
+        .with_context(|| format!("Cannot advance '{}' watermark", kind))?;
+
+    Ok(row.map(|row| row.try_get("digest_id")).transpose()?)
+}
+
+/// See [`advance_watermark`].
+pub async fn select_watermark(db: &Pool, kind: &str) -> Result<Option<i64>> {
+    let row = db
+        .get()
+        .await?
+        .query_opt("SELECT digest_id FROM watermarks WHERE kind = $1", &[&kind])
+        .await
+        .with_context(|| format!("Cannot select '{}' watermark", kind))?;
+
+    Ok(row.map(|row| row.try_get("digest_id")).transpose()?)
+}
+
+/// Selects up to `limit` done jobs of given kind with digest id between given
+/// ids inclusive, ordered by digest id.
+pub async fn select_done_in_digest_id_range(
+    db: &Pool,
+    kind: &str,
+    from_digest_id: i64,
+    to_digest_id: i64,
+    limit: i64,
+) -> Result<Vec<Job>> {
+    let query = "
+        SELECT
+            id, digest_id, digest, attempts
+        FROM
+            jobs
+        WHERE
+            kind = $1 AND status = $2 AND digest_id BETWEEN $3 AND $4
+        ORDER BY
+            digest_id ASC
+        LIMIT $5";
+
+    let rows = db
+        .get()
+        .await?
+        .query(
+            query,
+            &[
+                &kind,
+                &(JobStatus::Done as i16),
+                &from_digest_id,
+                &to_digest_id,
+                &limit,
+            ],
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "Cannot select done '{}' jobs with digest ids {}..={}",
+                kind, from_digest_id, to_digest_id
+            )
+        })?;
+
+    rows.into_iter().map(job_from_row).collect()
+}
+
+fn job_from_row(row: tokio_postgres::Row) -> Result<Job> {
+    Ok(Job {
+        id: row.try_get("id")?,
+        digest_id: row.try_get("digest_id")?,
+        digest: row.try_get("digest")?,
+        attempts: row.try_get("attempts")?,
+    })
+}
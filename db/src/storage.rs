@@ -0,0 +1,294 @@
+//! Services which only need the core of the persistence can be written
+//! against [`Storage`] instead of the free fns, so that they run without
+//! Postgres, e.g. locally or in tests, see [`crate::MemoryStorage`].
+//!
+//! [`PgStorage`] is the default. It delegates to the free fns, which remain
+//! the place for everything Postgres specific, e.g. archival or partitions.
+//! Services pick the implementation with [`StorageBackend::from_env`].
+
+use crate::queue::{self, Job};
+use crate::{partitions, Pool, SuiTx};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use misc::Digest;
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+
+/// Which [`Storage`] a service runs against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// See [`PgStorage`].
+    Postgres,
+    /// See [`crate::MemoryStorage`]. Nothing is shared between processes.
+    Memory,
+}
+
+impl StorageBackend {
+    /// Reads given env var, either "postgres" or "memory". Defaults to
+    /// [`StorageBackend::Postgres`].
+    pub fn from_env(key: &str) -> Result<Self> {
+        match env::var(key).ok().as_deref() {
+            None | Some("postgres") => Ok(Self::Postgres),
+            Some("memory") => Ok(Self::Memory),
+            Some(other) => bail!(
+                "Invalid {} '{}', expected \"postgres\" or \"memory\"",
+                key,
+                other
+            ),
+        }
+    }
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Inserts digests in given order. Digests which are already stored, or
+    /// repeated in the batch, are skipped.
+    async fn insert_digests(&self, digests: &[Digest]) -> Result<()>;
+
+    /// Like [`Storage::insert_digests`], for batches which might be large,
+    /// e.g. when a support is promoted.
+    async fn bulk_insert_digests(&self, digests: &[Digest]) -> Result<()> {
+        self.insert_digests(digests).await
+    }
+
+    /// Up to `limit` digests stored after given one, ordered by id.
+    async fn select_digests_since_exclusive(
+        &self,
+        digest: &Digest,
+        limit: usize,
+    ) -> Result<Vec<Digest>>;
+
+    /// Like [`Storage::select_digests_since_exclusive`], but starts with given
+    /// digest.
+    async fn select_digests_since_inclusive(
+        &self,
+        digest: &Digest,
+        limit: usize,
+    ) -> Result<Vec<Digest>>;
+
+    /// Up to `limit` digests with position greater than given one, ordered by
+    /// position.
+    async fn select_digests_after_position(
+        &self,
+        position: i64,
+        limit: usize,
+    ) -> Result<Vec<(i64, Digest)>>;
+
+    async fn has_digest(&self, digest: &Digest) -> Result<bool>;
+
+    /// See [`crate::select_stored_digests`].
+    async fn select_stored_digests(
+        &self,
+        digests: &[Digest],
+    ) -> Result<Vec<(i64, Digest)>>;
+
+    /// See [`queue::enqueue_from_digests`].
+    async fn enqueue_from_digests(&self, kind: &str, limit: i64)
+        -> Result<u64>;
+
+    /// See [`queue::claim`].
+    async fn claim(
+        &self,
+        kind: &str,
+        worker_id: &str,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<Job>>;
+
+    /// See [`queue::complete`].
+    async fn complete(&self, worker_id: &str, ids: &[i64]) -> Result<Vec<i64>>;
+
+    /// See [`queue::fail`].
+    async fn fail(
+        &self,
+        worker_id: &str,
+        ids: &[i64],
+        max_attempts: i32,
+    ) -> Result<()>;
+
+    /// See [`queue::fail_abandoned`].
+    async fn fail_abandoned(
+        &self,
+        kind: &str,
+        max_attempts: i32,
+    ) -> Result<u64>;
+
+    /// Completes and fails given jobs, and inserts the txs of the jobs which
+    /// were completed, all at once. Txs are keyed by job id. Returns ids of
+    /// the completed jobs, see [`queue::complete`].
+    async fn finish_jobs(
+        &self,
+        worker_id: &str,
+        completed: &[i64],
+        failed: &[i64],
+        max_attempts: i32,
+        txs: HashMap<i64, SuiTx>,
+    ) -> Result<Vec<i64>>;
+
+    /// See [`queue::advance_watermark`].
+    async fn advance_watermark(&self, kind: &str) -> Result<Option<i64>>;
+
+    /// See [`queue::select_watermark`].
+    async fn select_watermark(&self, kind: &str) -> Result<Option<i64>>;
+
+    /// Fails if a tx of the same order is stored already.
+    async fn insert_txs(&self, txs: &[SuiTx]) -> Result<()>;
+}
+
+pub struct PgStorage {
+    pool: Pool,
+}
+
+impl PgStorage {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn insert_digests(&self, digests: &[Digest]) -> Result<()> {
+        if digests.is_empty() {
+            return Ok(());
+        }
+
+        crate::insert_digests(&self.pool, digests).await
+    }
+
+    async fn bulk_insert_digests(&self, digests: &[Digest]) -> Result<()> {
+        crate::bulk_insert_digests(&self.pool, digests).await
+    }
+
+    async fn select_digests_since_exclusive(
+        &self,
+        digest: &Digest,
+        limit: usize,
+    ) -> Result<Vec<Digest>> {
+        crate::select_digests_since_exclusive(&self.pool, digest, limit).await
+    }
+
+    async fn select_digests_since_inclusive(
+        &self,
+        digest: &Digest,
+        limit: usize,
+    ) -> Result<Vec<Digest>> {
+        crate::select_digests_since_inclusive(&self.pool, digest, limit).await
+    }
+
+    async fn select_digests_after_position(
+        &self,
+        position: i64,
+        limit: usize,
+    ) -> Result<Vec<(i64, Digest)>> {
+        crate::select_digests_after_position(&self.pool, position, limit).await
+    }
+
+    async fn has_digest(&self, digest: &Digest) -> Result<bool> {
+        crate::has_digest(&self.pool, digest).await
+    }
+
+    async fn select_stored_digests(
+        &self,
+        digests: &[Digest],
+    ) -> Result<Vec<(i64, Digest)>> {
+        crate::select_stored_digests(&self.pool, digests).await
+    }
+
+    async fn enqueue_from_digests(
+        &self,
+        kind: &str,
+        limit: i64,
+    ) -> Result<u64> {
+        queue::enqueue_from_digests(&self.pool, kind, limit).await
+    }
+
+    async fn claim(
+        &self,
+        kind: &str,
+        worker_id: &str,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<Job>> {
+        queue::claim(&self.pool, kind, worker_id, limit, lease).await
+    }
+
+    async fn complete(&self, worker_id: &str, ids: &[i64]) -> Result<Vec<i64>> {
+        let client = self.pool.get().await?;
+        queue::complete(&**client, worker_id, ids).await
+    }
+
+    async fn fail(
+        &self,
+        worker_id: &str,
+        ids: &[i64],
+        max_attempts: i32,
+    ) -> Result<()> {
+        let client = self.pool.get().await?;
+        queue::fail(&**client, worker_id, ids, max_attempts).await
+    }
+
+    async fn fail_abandoned(
+        &self,
+        kind: &str,
+        max_attempts: i32,
+    ) -> Result<u64> {
+        queue::fail_abandoned(&self.pool, kind, max_attempts).await
+    }
+
+    async fn finish_jobs(
+        &self,
+        worker_id: &str,
+        completed: &[i64],
+        failed: &[i64],
+        max_attempts: i32,
+        mut txs: HashMap<i64, SuiTx>,
+    ) -> Result<Vec<i64>> {
+        // creating a partition would lock the txs table for the whole
+        // transaction
+        partitions::create_txs_partitions(
+            &self.pool,
+            txs.values().map(|tx| tx.order),
+        )
+        .await?;
+
+        let mut client = self.pool.get().await?;
+        let db_tx = client.transaction().await?;
+
+        let (completed, _) = tokio::try_join!(
+            queue::complete(&db_tx, worker_id, completed),
+            queue::fail(&db_tx, worker_id, failed, max_attempts),
+        )?;
+
+        let txs: Vec<_> =
+            completed.iter().filter_map(|id| txs.remove(id)).collect();
+        crate::insert_txs(&db_tx, &txs).await?;
+
+        db_tx.commit().await?;
+
+        Ok(completed)
+    }
+
+    async fn advance_watermark(&self, kind: &str) -> Result<Option<i64>> {
+        queue::advance_watermark(&self.pool, kind).await
+    }
+
+    async fn select_watermark(&self, kind: &str) -> Result<Option<i64>> {
+        queue::select_watermark(&self.pool, kind).await
+    }
+
+    async fn insert_txs(&self, txs: &[SuiTx]) -> Result<()> {
+        partitions::create_txs_partitions(
+            &self.pool,
+            txs.iter().map(|tx| tx.order),
+        )
+        .await?;
+
+        let client = self.pool.get().await?;
+        crate::insert_txs(&**client, txs).await
+    }
+}
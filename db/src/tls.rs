@@ -0,0 +1,86 @@
+//! Managed Postgres instances usually require TLS. The server certificate is
+//! verified against the system's CA certificates and optionally a custom CA
+//! certificate, e.g. a self-signed one. Servers which verify clients also
+//! need a client certificate.
+//!
+//! Whether TLS is required is up to the `sslmode` of the connection conf. The
+//! default `prefer` falls back to a plain connection if the server doesn't
+//! support TLS, `sslmode=require` does not.
+
+use anyhow::{bail, Context, Result};
+use misc::env_var;
+use native_tls::{Certificate, Identity, TlsConnector};
+use postgres_native_tls::MakeTlsConnector;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, Default)]
+pub struct TlsConf {
+    /// If not set, connections are never encrypted.
+    pub enabled: bool,
+    /// PEM encoded CA certificate to trust on top of the system ones.
+    pub ca_cert: Option<PathBuf>,
+    /// PEM encoded client certificate, requires [`TlsConf::client_key`].
+    pub client_cert: Option<PathBuf>,
+    /// PEM encoded PKCS #8 private key of the client certificate.
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsConf {
+    /// Reads following env vars, none of which is required:
+    /// - `{prefix}`, "true" to enable TLS
+    /// - `{prefix}_CA_CERT`
+    /// - `{prefix}_CLIENT_CERT`
+    /// - `{prefix}_CLIENT_KEY`
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        let key = |name: &str| format!("{}_{}", prefix, name);
+
+        let conf = Self {
+            enabled: env_var(prefix)?.unwrap_or(false),
+            ca_cert: env_var(&key("CA_CERT"))?,
+            client_cert: env_var(&key("CLIENT_CERT"))?,
+            client_key: env_var(&key("CLIENT_KEY"))?,
+        };
+
+        if conf.client_cert.is_some() != conf.client_key.is_some() {
+            bail!(
+                "{}_CLIENT_CERT and {}_CLIENT_KEY must be set together",
+                prefix,
+                prefix
+            );
+        }
+        let has_certs = conf.ca_cert.is_some() || conf.client_cert.is_some();
+        if has_certs && !conf.enabled {
+            bail!("Certificates are set but {} is not \"true\"", prefix);
+        }
+
+        Ok(conf)
+    }
+
+    pub(crate) fn connector(&self) -> Result<MakeTlsConnector> {
+        let mut builder = TlsConnector::builder();
+
+        if let Some(path) = &self.ca_cert {
+            let cert = Certificate::from_pem(&read(path)?)
+                .with_context(|| format!("Invalid CA cert {:?}", path))?;
+            builder.add_root_certificate(cert);
+        }
+
+        if let (Some(cert_path), Some(key_path)) =
+            (&self.client_cert, &self.client_key)
+        {
+            let identity =
+                Identity::from_pkcs8(&read(cert_path)?, &read(key_path)?)
+                    .with_context(|| {
+                        format!("Invalid client cert {:?}", cert_path)
+                    })?;
+            builder.identity(identity);
+        }
+
+        Ok(MakeTlsConnector::new(builder.build()?))
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("Cannot read {:?}", path))
+}
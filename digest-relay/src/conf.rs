@@ -0,0 +1,110 @@
+use crate::prelude::*;
+use rdkafka::producer::FutureProducer;
+use rdkafka::ClientConfig;
+use std::{env, time::Duration};
+
+pub mod consts {
+    use std::time::Duration;
+
+    /// How long to wait before polling db again if there are no new digests.
+    pub const SLEEP_ON_NO_DIGESTS: Duration = Duration::from_millis(500);
+
+    /// How long a standby relay waits before trying to take the lock again.
+    pub const SLEEP_ON_LOCKED: Duration = Duration::from_secs(5);
+
+    /// One connection holds the relay's lock, the other reads digests.
+    pub const DB_POOL_SIZE: usize = 2;
+
+    pub mod defaults {
+        use super::Duration;
+
+        pub const RELAY_NAME: &str = "digests";
+        pub const BATCH_SIZE: usize = 1_000;
+        pub const KAFKA_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Conf {
+    /// e.g. `"host=localhost user=postgres"`, see
+    /// [`tokio_postgres::config::Config`] on the specific format
+    pub writer_conn_conf: String,
+    /// See [`db::TlsConf::from_env`].
+    pub db_tls: db::TlsConf,
+    /// Instances with the same name take over from each other, see
+    /// [`db::offsets`]. Relays of different names publish independently.
+    pub relay_name: String,
+    /// Comma separated, e.g. `localhost:9092`.
+    pub kafka_brokers: String,
+    pub kafka_topic: String,
+    /// How many digests are published before the offset is committed.
+    pub batch_size: usize,
+    /// How long a message can wait in the producer's queue before it's given
+    /// up on.
+    pub kafka_send_timeout: Duration,
+}
+
+impl Conf {
+    pub fn from_env() -> Result<Self> {
+        let writer_conn_conf =
+            env::var("WRITER_CONN_CONF").context("Writer DB URL")?;
+
+        let db_tls = db::TlsConf::from_env("DB_TLS").context("Db TLS")?;
+        info!("Db TLS: {:?}", db_tls);
+
+        let relay_name = env::var("RELAY_NAME")
+            .unwrap_or_else(|_| consts::defaults::RELAY_NAME.to_string());
+        info!("Relay name: {}", relay_name);
+
+        let kafka_brokers =
+            env::var("KAFKA_BROKERS").context("Kafka brokers")?;
+        info!("Kafka brokers: {}", kafka_brokers);
+
+        let kafka_topic = env::var("KAFKA_TOPIC").context("Kafka topic")?;
+        info!("Kafka topic: {}", kafka_topic);
+
+        let batch_size = env::var("BATCH_SIZE")
+            .ok()
+            .map(|s| s.parse::<usize>())
+            .transpose()
+            .context("Invalid batch size")?
+            .unwrap_or(consts::defaults::BATCH_SIZE);
+        if batch_size == 0 {
+            bail!("Batch size must be positive");
+        }
+        info!("Batch size: {}", batch_size);
+
+        let kafka_send_timeout = env::var("KAFKA_SEND_TIMEOUT_MS")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()
+            .context("Invalid Kafka send timeout")?
+            .map(Duration::from_millis)
+            .unwrap_or(consts::defaults::KAFKA_SEND_TIMEOUT);
+        info!("Kafka send timeout: {:?}", kafka_send_timeout);
+
+        Ok(Self {
+            writer_conn_conf,
+            db_tls,
+            relay_name,
+            kafka_brokers,
+            kafka_topic,
+            batch_size,
+            kafka_send_timeout,
+        })
+    }
+
+    pub fn db(&self) -> Result<DbPool> {
+        db::pool(&self.writer_conn_conf, consts::DB_POOL_SIZE, &self.db_tls)
+    }
+
+    /// With idempotence, retried sends neither duplicate nor reorder messages
+    /// within a partition.
+    pub fn producer(&self) -> Result<FutureProducer> {
+        ClientConfig::new()
+            .set("bootstrap.servers", &self.kafka_brokers)
+            .set("enable.idempotence", "true")
+            .create()
+            .context("Cannot create Kafka producer")
+    }
+}
@@ -0,0 +1,137 @@
+//! Publishes digests to a Kafka topic in order of their position, see the
+//! ordering section of the [`db`] crate docs.
+//!
+//! The relay tails the `digests` table rather than hooking into the leader,
+//! so digests inserted by a promoted support or by a backfill are published
+//! too.
+//!
+//! Each message is keyed by the base58 digest and its payload holds the
+//! position. After each batch is acknowledged by the brokers, the relay
+//! commits the position it got to, see [`db::offsets`]. If a relay dies, a
+//! standby instance of the same name continues from the committed position.
+//! Digests published after the last commit are published again, so consumers
+//! see each digest at least once and should deduplicate by position.
+
+mod conf;
+mod prelude;
+
+use conf::{consts, Conf};
+use futures::future;
+use misc::Shutdown;
+use prelude::*;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use serde::Serialize;
+use tokio::time::sleep;
+
+/// Payload of a message.
+#[derive(Serialize)]
+struct RelayedDigest<'a> {
+    position: i64,
+    /// Base58, same as the message key.
+    digest: &'a str,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+
+    env_logger::init(); // set up with env RUST_LOG
+
+    let conf = Conf::from_env().context("Cannot read env vars")?;
+    let name = &conf.relay_name;
+
+    let shutdown = Shutdown::listen()?;
+
+    let db = conf.db()?;
+    let producer = conf.producer()?;
+
+    // the lock is held for as long as this connection is open, therefore we
+    // keep it and commit offsets over it, so that we stop if the connection
+    // and with it the lock is lost
+    let lock = db.get().await?;
+    while !db::offsets::try_lock(&**lock, name).await? {
+        info!("Relay '{}' is locked by another instance, waiting", name);
+        tokio::select! {
+            _ = sleep(consts::SLEEP_ON_LOCKED) => (),
+            _ = shutdown.requested() => return Ok(()),
+        };
+    }
+
+    // positions start at 0
+    let mut position = db::offsets::select(&**lock, name).await?.unwrap_or(-1);
+    info!("Relaying digests after position {}", position);
+
+    // each batch is committed before we check for shutdown
+    while !shutdown.is_requested() {
+        let digests =
+            db::select_digests_after_position(&db, position, conf.batch_size)
+                .await?;
+        let last_position = match digests.last() {
+            Some((last_position, _)) => *last_position,
+            None => {
+                tokio::select! {
+                    _ = sleep(consts::SLEEP_ON_NO_DIGESTS) => (),
+                    _ = shutdown.requested() => (),
+                };
+                continue;
+            }
+        };
+
+        // retention doesn't archive what a relay hasn't published yet, so
+        // this only happens if the relay started after the archival
+        let positions: Vec<_> = digests.iter().map(|(p, _)| *p).collect();
+        if let Some(hole) = db::position_holes(position, &positions).first() {
+            bail!(
+                "Digests at positions {:?} were archived before being relayed",
+                hole
+            );
+        }
+
+        publish(&conf, &producer, &digests).await?;
+        db::offsets::commit(&**lock, name, last_position).await?;
+        position = last_position;
+    }
+
+    info!("Stopped relaying at position {}", position);
+
+    Ok(())
+}
+
+/// Returns once all messages are acknowledged by the brokers.
+async fn publish(
+    conf: &Conf,
+    producer: &FutureProducer,
+    digests: &[(i64, Digest)],
+) -> Result<()> {
+    let messages = digests
+        .iter()
+        .map(|(position, digest)| {
+            let key = digest.to_string();
+            let payload = serde_json::to_vec(&RelayedDigest {
+                position: *position,
+                digest: &key,
+            })?;
+
+            Ok((key, payload))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let deliveries = messages.iter().map(|(key, payload)| {
+        producer.send(
+            FutureRecord::to(&conf.kafka_topic)
+                .key(key)
+                .payload(payload),
+            Timeout::After(conf.kafka_send_timeout),
+        )
+    });
+    for (delivery, (position, _)) in
+        future::join_all(deliveries).await.into_iter().zip(digests)
+    {
+        delivery.map_err(|(e, _)| e).with_context(|| {
+            format!("Cannot publish digest at position {}", position)
+        })?;
+    }
+
+    Ok(())
+}
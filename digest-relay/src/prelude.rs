@@ -0,0 +1,4 @@
+pub use anyhow::{anyhow, bail, Context, Result};
+pub use db::Pool as DbPool;
+pub use log::{error, info, warn};
+pub use misc::Digest;
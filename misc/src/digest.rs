@@ -0,0 +1,141 @@
+//! Digests are stored as `BYTEA` in db and shown as base58 in logs, which is
+//! how explorers show them too.
+
+use anyhow::{anyhow, Error, Result};
+use bytes::BytesMut;
+use postgres_types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+use std::fmt;
+use std::str::FromStr;
+use sui_sdk::types::base_types::TransactionDigest;
+
+const LENGTH: usize = 32;
+
+/// Digest of a tx. The length is checked whenever we get one from outside,
+/// e.g. from db.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Digest([u8; LENGTH]);
+
+impl Digest {
+    pub const LENGTH: usize = LENGTH;
+
+    pub fn new(bytes: [u8; Self::LENGTH]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; Self::LENGTH] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Digest {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl TryFrom<&[u8]> for Digest {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        Ok(Self(bytes.try_into().map_err(|_| {
+            anyhow!(
+                "Digest must have {} bytes, got {}",
+                Self::LENGTH,
+                bytes.len()
+            )
+        })?))
+    }
+}
+
+impl From<TransactionDigest> for Digest {
+    fn from(digest: TransactionDigest) -> Self {
+        // the lengths match, otherwise the conversion the other way around
+        // wouldn't compile
+        let mut bytes = [0; Self::LENGTH];
+        bytes.copy_from_slice(&digest.to_bytes());
+        Self(bytes)
+    }
+}
+
+impl From<Digest> for TransactionDigest {
+    fn from(digest: Digest) -> Self {
+        TransactionDigest::new(digest.0)
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", bs58::encode(&self.0).into_string())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Digest({})", self)
+    }
+}
+
+impl FromStr for Digest {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = bs58::decode(s)
+            .into_vec()
+            .map_err(|e| anyhow!("Digest '{}' is not base58: {}", s, e))?;
+
+        Self::try_from(bytes.as_slice())
+    }
+}
+
+impl ToSql for Digest {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        self.0.as_slice().to_sql(ty, out)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&[u8] as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for Digest {
+    fn from_sql(
+        ty: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        let bytes = <&[u8] as FromSql>::from_sql(ty, raw)?;
+
+        Ok(Self::try_from(bytes)?)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&[u8] as FromSql>::accepts(ty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_base58() {
+        let digest = Digest::new([7; Digest::LENGTH]);
+
+        let s = digest.to_string();
+        assert_eq!(s.parse::<Digest>().unwrap(), digest);
+        assert_eq!(format!("{:?}", digest), format!("Digest({})", s));
+    }
+
+    #[test]
+    fn it_checks_length() {
+        assert!(Digest::try_from([1; 31].as_slice()).is_err());
+        assert!(Digest::try_from([1; 33].as_slice()).is_err());
+        assert!("3mJr7AoUXx2Wqd".parse::<Digest>().is_err());
+        assert!("not base58 0OIl".parse::<Digest>().is_err());
+    }
+}
@@ -4,35 +4,26 @@ pub use sui_sdk;
 
 pub use sui_sdk::rpc_types::GatewayTxSeqNumber as SeqNum;
 
-/// A digest is [u8; 32] but this type is more convenient to work with in the
-/// context of db query params.
-pub type Digest = Vec<u8>;
+mod digest;
+mod retry;
+mod shutdown;
 
-use anyhow::Result;
-use futures::Future;
-use tokio::time::{sleep, Duration};
+pub use digest::Digest;
+pub use retry::{retry, Retry, RetryPolicy};
+pub use shutdown::{Shutdown, FORCED_EXIT_CODE};
 
-pub async fn retry<T, F>(
-    mut job: impl FnMut() -> F,
-    max_retries: usize,
-    mut wait_ms: u64,
-    exponential_backoff_multiplier: u64,
-) -> Result<T>
+use anyhow::{Context, Result};
+use std::{env, str::FromStr};
+
+/// Parses env var if it's set.
+pub fn env_var<T>(key: &str) -> Result<Option<T>>
 where
-    F: Future<Output = Result<T>>,
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
 {
-    assert_ne!(max_retries, 0);
-    assert_ne!(exponential_backoff_multiplier, 0);
-
-    let mut retries = max_retries;
-    loop {
-        match job().await {
-            Err(_) if retries > 0 => {
-                retries -= 1;
-                sleep(Duration::from_millis(wait_ms)).await;
-                wait_ms *= exponential_backoff_multiplier;
-            }
-            res => return res,
-        }
-    }
+    env::var(key)
+        .ok()
+        .map(|s| s.parse::<T>())
+        .transpose()
+        .with_context(|| format!("Invalid {}", key))
 }
@@ -0,0 +1,231 @@
+//! Retrying fallible async jobs with an exponential back-off.
+//!
+//! Not every error is worth retrying. The caller classifies errors with
+//! [`Retry`], e.g. a dropped connection is [`Retry::Transient`] while a
+//! response which cannot be deserialized will not get any better and is
+//! [`Retry::Permanent`].
+
+use crate::env_var;
+use anyhow::{bail, Result};
+use futures::Future;
+use rand::Rng;
+use tokio::time::{sleep, Duration, Instant};
+
+/// How an error should be treated by [`retry`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retry {
+    /// Retrying might help.
+    Transient,
+    /// Return the error right away.
+    Permanent,
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// How many times a job is retried after the first attempt.
+    pub max_retries: usize,
+    /// How long to wait before the first retry.
+    pub initial_backoff: Duration,
+    /// Each following wait is this many times longer than the previous one.
+    pub backoff_multiplier: u32,
+    /// No single wait is longer than this.
+    pub max_backoff: Duration,
+    /// If set, we give up once the next retry would happen after this long
+    /// since the first attempt.
+    pub max_elapsed: Option<Duration>,
+    /// Each wait is randomized by up to this fraction of its length in either
+    /// direction, so that many clients don't retry in lockstep. Between 0 and
+    /// 1.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    /// 1st retry after 10ms
+    /// 2nd retry after 100ms
+    /// 3rd retry after 1s
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(10),
+            backoff_multiplier: 10,
+            max_backoff: Duration::from_secs(10),
+            max_elapsed: None,
+            jitter: 0.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Reads following env vars, each of which defaults to
+    /// [`RetryPolicy::default`]:
+    /// - `{prefix}_MAX_RETRIES`
+    /// - `{prefix}_INITIAL_BACKOFF_MS`
+    /// - `{prefix}_BACKOFF_MULTIPLIER`
+    /// - `{prefix}_MAX_BACKOFF_MS`
+    /// - `{prefix}_MAX_ELAPSED_MS`
+    /// - `{prefix}_JITTER`
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        let default = Self::default();
+        let key = |name: &str| format!("{}_{}", prefix, name);
+
+        let policy = Self {
+            max_retries: env_var(&key("MAX_RETRIES"))?
+                .unwrap_or(default.max_retries),
+            initial_backoff: env_var(&key("INITIAL_BACKOFF_MS"))?
+                .map(Duration::from_millis)
+                .unwrap_or(default.initial_backoff),
+            backoff_multiplier: env_var(&key("BACKOFF_MULTIPLIER"))?
+                .unwrap_or(default.backoff_multiplier),
+            max_backoff: env_var(&key("MAX_BACKOFF_MS"))?
+                .map(Duration::from_millis)
+                .unwrap_or(default.max_backoff),
+            max_elapsed: env_var(&key("MAX_ELAPSED_MS"))?
+                .map(Duration::from_millis)
+                .or(default.max_elapsed),
+            jitter: env_var(&key("JITTER"))?.unwrap_or(default.jitter),
+        };
+
+        if policy.backoff_multiplier == 0 {
+            bail!("{}_BACKOFF_MULTIPLIER must be positive", prefix);
+        }
+        if !(0.0..=1.0).contains(&policy.jitter) {
+            bail!("{}_JITTER must be between 0 and 1", prefix);
+        }
+
+        Ok(policy)
+    }
+
+    /// How long to wait before given retry (0 being the first), not counting
+    /// jitter.
+    fn backoff(&self, retry: usize) -> Duration {
+        let multiplier = (self.backoff_multiplier as u64)
+            .checked_pow(retry as u32)
+            .unwrap_or(u64::MAX);
+
+        Duration::from_millis(
+            (self.initial_backoff.as_millis() as u64)
+                .saturating_mul(multiplier),
+        )
+        .min(self.max_backoff)
+    }
+
+    fn backoff_with_jitter(&self, retry: usize) -> Duration {
+        let backoff = self.backoff(retry);
+        if self.jitter == 0.0 {
+            return backoff;
+        }
+
+        let factor = rand::thread_rng()
+            .gen_range((1.0 - self.jitter)..=(1.0 + self.jitter));
+        backoff.mul_f64(factor).min(self.max_backoff)
+    }
+}
+
+/// Runs the job until it succeeds, it returns an error which `classify` deems
+/// [`Retry::Permanent`] or the policy runs out of retries.
+pub async fn retry<T, F>(
+    policy: &RetryPolicy,
+    classify: impl Fn(&anyhow::Error) -> Retry,
+    mut job: impl FnMut() -> F,
+) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let started_at = Instant::now();
+
+    let mut retries = 0;
+    loop {
+        match job().await {
+            Err(e)
+                if retries < policy.max_retries
+                    && classify(&e) == Retry::Transient =>
+            {
+                let wait = policy.backoff_with_jitter(retries);
+                let exceeds_max_elapsed = policy
+                    .max_elapsed
+                    .map(|max| started_at.elapsed() + wait > max)
+                    .unwrap_or(false);
+                if exceeds_max_elapsed {
+                    return Err(e);
+                }
+
+                retries += 1;
+                sleep(wait).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_caps_backoff() {
+        let policy = RetryPolicy {
+            max_backoff: Duration::from_millis(500),
+            ..Default::default()
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(10));
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(500));
+        assert_eq!(policy.backoff(100), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn it_keeps_jitter_within_bounds() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..Default::default()
+        };
+
+        for _ in 0..100 {
+            let wait = policy.backoff_with_jitter(1);
+            assert!(wait >= Duration::from_millis(50));
+            assert!(wait <= Duration::from_millis(150));
+        }
+    }
+
+    #[tokio::test]
+    async fn it_does_not_retry_permanent_errors() {
+        let policy = RetryPolicy::default();
+        let mut attempts = 0;
+
+        let res: Result<()> = retry(
+            &policy,
+            |_| Retry::Permanent,
+            || {
+                attempts += 1;
+                async { bail!("not found") }
+            },
+        )
+        .await;
+
+        assert!(res.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn it_retries_transient_errors() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let mut attempts = 0;
+
+        let res: Result<()> = retry(
+            &policy,
+            |_| Retry::Transient,
+            || {
+                attempts += 1;
+                async { bail!("connection reset") }
+            },
+        )
+        .await;
+
+        assert!(res.is_err());
+        assert_eq!(attempts, 1 + policy.max_retries);
+    }
+}
@@ -0,0 +1,61 @@
+//! Deploys stop services with SIGTERM, and SIGINT is what we get on ctrl-c.
+//! Instead of dying in the middle of a batch, services check [`Shutdown`]
+//! between batches and exit once the current one is persisted.
+//!
+//! A second signal exits right away with [`FORCED_EXIT_CODE`].
+
+use anyhow::Result;
+use log::{info, warn};
+use std::future;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// 128 + SIGINT, as shells report a process interrupted with ctrl-c.
+pub const FORCED_EXIT_CODE: i32 = 130;
+
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Spawns a task which waits for SIGTERM or SIGINT.
+    pub fn listen() -> Result<Self> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let (tx, rx) = watch::channel(false);
+
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = sigterm.recv() => info!("Received SIGTERM"),
+                _ = sigint.recv() => info!("Received SIGINT"),
+            };
+            // the receivers are never all dropped while the service runs
+            let _ = tx.send(true);
+
+            tokio::select! {
+                _ = sigterm.recv() => (),
+                _ = sigint.recv() => (),
+            };
+            warn!("Received second signal, exiting without cleanup");
+            std::process::exit(FORCED_EXIT_CODE);
+        });
+
+        Ok(Self { requested: rx })
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Resolves once shutdown is requested, never otherwise.
+    pub async fn requested(&self) {
+        let mut requested = self.requested.clone();
+        while !*requested.borrow() {
+            if requested.changed().await.is_err() {
+                // the signal task is gone without having sent anything
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Fetching many txs in one round trip with a JSON-RPC batch request.
+//!
+//! The node responds to each request of the batch on its own, so one tx
+//! failing doesn't fail the others. If the batch as a whole fails, e.g.
+//! because the node does not support batch requests, we fall back to fetching
+//! the txs one by one.
+
+use crate::{fetch_tx, Client};
+use anyhow::{anyhow, Result};
+use futures::future;
+use log::warn;
+use misc::sui_sdk::{
+    rpc_types::SuiTransactionResponse, types::base_types::TransactionDigest,
+};
+use misc::Digest;
+use serde::{Deserialize, Serialize};
+
+const GET_TRANSACTION_METHOD: &str = "sui_getTransaction";
+
+#[derive(Serialize)]
+struct Request {
+    jsonrpc: &'static str,
+    /// Index into the digests of the batch.
+    id: usize,
+    method: &'static str,
+    params: [serde_json::Value; 1],
+}
+
+#[derive(Deserialize)]
+struct Response {
+    id: usize,
+    result: Option<serde_json::Value>,
+    error: Option<ResponseError>,
+}
+
+#[derive(Deserialize)]
+struct ResponseError {
+    code: i64,
+    message: String,
+}
+
+/// Fetches txs of given digests. The returned vector has one result per
+/// digest, in the same order.
+///
+/// Only the txs which are not in the client's cache are requested from the
+/// node.
+pub async fn fetch_txs(
+    sui: &Client,
+    digests: &[Digest],
+) -> Vec<Result<SuiTransactionResponse>> {
+    let mut txs = Vec::with_capacity(digests.len());
+    for digest in digests {
+        txs.push(sui.cached_tx(digest).await.map(Ok));
+    }
+
+    let missing: Vec<_> = digests
+        .iter()
+        .zip(&txs)
+        .filter(|(_, tx)| tx.is_none())
+        .map(|(digest, _)| *digest)
+        .collect();
+    let mut fetched = fetch_uncached_txs(sui, &missing).await.into_iter();
+    for (digest, tx) in digests.iter().zip(&mut txs) {
+        if tx.is_none() {
+            // there's one fetched tx per missing digest
+            let fetched_tx = fetched.next().unwrap();
+            if let Ok(fetched_tx) = &fetched_tx {
+                sui.cache_tx(digest, fetched_tx).await;
+            }
+            *tx = Some(fetched_tx);
+        }
+    }
+
+    // every tx is either cached or fetched by now
+    txs.into_iter().map(Option::unwrap).collect()
+}
+
+async fn fetch_uncached_txs(
+    sui: &Client,
+    digests: &[Digest],
+) -> Vec<Result<SuiTransactionResponse>> {
+    if digests.is_empty() {
+        return vec![];
+    }
+
+    // the node serves each request of the batch, so the batch counts as that
+    // many requests towards the rate limit
+    match sui
+        .retry_batch(digests.len(), || request_batch(sui, digests))
+        .await
+    {
+        Ok(txs) => txs,
+        Err(e) => {
+            warn!(
+                "Batch request to '{}' failed, fetching {} txs one by one: {}",
+                sui.url(),
+                digests.len(),
+                e
+            );
+
+            // these are cached by fetch_tx already, caching them again is
+            // harmless
+            future::join_all(digests.iter().map(|digest| fetch_tx(sui, digest)))
+                .await
+        }
+    }
+}
+
+async fn request_batch(
+    sui: &Client,
+    digests: &[Digest],
+) -> Result<Vec<Result<SuiTransactionResponse>>> {
+    let requests = digests
+        .iter()
+        .enumerate()
+        .map(|(id, digest)| {
+            let digest = TransactionDigest::from(*digest);
+
+            Ok(Request {
+                jsonrpc: "2.0",
+                id,
+                method: GET_TRANSACTION_METHOD,
+                params: [serde_json::to_value(digest)?],
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let responses: Vec<Response> = sui
+        .http
+        .post(sui.url())
+        .json(&requests)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // responses can come in any order
+    let mut txs: Vec<Option<Result<SuiTransactionResponse>>> =
+        digests.iter().map(|_| None).collect();
+    for response in responses {
+        let tx = txs.get_mut(response.id).ok_or_else(|| {
+            anyhow!("Unexpected response id {} in batch", response.id)
+        })?;
+
+        *tx = Some(match (response.result, response.error) {
+            (_, Some(error)) => {
+                Err(anyhow!("RPC error {}: {}", error.code, error.message))
+            }
+            (Some(result), None) => {
+                serde_json::from_value(result).map_err(From::from)
+            }
+            (None, None) => {
+                Err(anyhow!("Neither result nor error in response"))
+            }
+        });
+    }
+
+    Ok(txs
+        .into_iter()
+        .map(|tx| tx.unwrap_or_else(|| Err(anyhow!("No response in batch"))))
+        .collect())
+}
@@ -0,0 +1,311 @@
+//! When a node degrades, retrying every call on its own only makes it worse.
+//!
+//! [`CircuitBreaker`] tracks outcomes of the recent calls to an endpoint. Once
+//! the share of failed calls crosses [`BreakerConf::error_rate`], the breaker
+//! opens and calls fail fast without reaching the node. After
+//! [`BreakerConf::open_for`] it half-opens and lets a single probe call
+//! through. If the probe succeeds, the breaker closes again, otherwise it stays
+//! open for another period.
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+use misc::env_var;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct BreakerConf {
+    /// How many of the most recent calls are considered.
+    pub window: usize,
+    /// The breaker doesn't open until there are at least this many calls in
+    /// the window.
+    pub min_calls: usize,
+    /// Share of failed calls in the window, between 0 and 1, at which the
+    /// breaker opens.
+    pub error_rate: f64,
+    /// How long the breaker stays open before letting a probe call through.
+    pub open_for: Duration,
+}
+
+impl Default for BreakerConf {
+    fn default() -> Self {
+        Self {
+            window: 100,
+            min_calls: 20,
+            error_rate: 0.5,
+            open_for: Duration::from_secs(5),
+        }
+    }
+}
+
+impl BreakerConf {
+    /// Reads following env vars, each of which defaults to
+    /// [`BreakerConf::default`]:
+    /// - `{prefix}_WINDOW`
+    /// - `{prefix}_MIN_CALLS`
+    /// - `{prefix}_ERROR_RATE`
+    /// - `{prefix}_OPEN_FOR_MS`
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        let default = Self::default();
+        let key = |name: &str| format!("{}_{}", prefix, name);
+
+        let conf = Self {
+            window: env_var(&key("WINDOW"))?.unwrap_or(default.window),
+            min_calls: env_var(&key("MIN_CALLS"))?.unwrap_or(default.min_calls),
+            error_rate: env_var(&key("ERROR_RATE"))?
+                .unwrap_or(default.error_rate),
+            open_for: env_var(&key("OPEN_FOR_MS"))?
+                .map(Duration::from_millis)
+                .unwrap_or(default.open_for),
+        };
+
+        if conf.window == 0 || conf.min_calls > conf.window {
+            bail!("{}_MIN_CALLS must not exceed {}_WINDOW", prefix, prefix);
+        }
+        if !(0.0..=1.0).contains(&conf.error_rate) {
+            bail!("{}_ERROR_RATE must be between 0 and 1", prefix);
+        }
+
+        Ok(conf)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Calls go through.
+    Closed,
+    /// Calls fail fast.
+    Open,
+    /// A single probe call goes through.
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "closed"),
+            Self::Open => write!(f, "open"),
+            Self::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+/// Returned instead of calling the node while the breaker is open.
+#[derive(Debug)]
+pub struct BreakerOpen {
+    pub url: String,
+}
+
+impl fmt::Display for BreakerOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Circuit breaker of '{}' is open", self.url)
+    }
+}
+
+impl std::error::Error for BreakerOpen {}
+
+pub struct CircuitBreaker {
+    url: String,
+    conf: BreakerConf,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// Whether each of the recent calls succeeded, newest at the back.
+    outcomes: VecDeque<bool>,
+    failures: usize,
+    /// Set while the breaker is open or half-open.
+    opened_at: Option<Instant>,
+    /// Set while a probe call is in flight. If the probe never reports back,
+    /// e.g. because its future was dropped, we let another one through after
+    /// [`BreakerConf::open_for`].
+    probe_started_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(url: &str, conf: BreakerConf) -> Self {
+        Self {
+            url: url.to_string(),
+            inner: Mutex::new(Inner {
+                outcomes: VecDeque::with_capacity(conf.window),
+                failures: 0,
+                opened_at: None,
+                probe_started_at: None,
+            }),
+            conf,
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state_at(Instant::now())
+    }
+
+    /// Errors with [`BreakerOpen`] if the call should not reach the node.
+    pub(crate) fn acquire(&self) -> Result<(), BreakerOpen> {
+        self.acquire_at(Instant::now())
+    }
+
+    /// Reports the outcome of a call which was let through by
+    /// [`CircuitBreaker::acquire`].
+    pub(crate) fn record(&self, success: bool) {
+        self.record_at(success, Instant::now())
+    }
+
+    fn state_at(&self, now: Instant) -> BreakerState {
+        let inner = self.inner.lock().unwrap();
+
+        match inner.opened_at {
+            None => BreakerState::Closed,
+            Some(opened_at) if now < opened_at + self.conf.open_for => {
+                BreakerState::Open
+            }
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    fn acquire_at(&self, now: Instant) -> Result<(), BreakerOpen> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let is_open = match inner.opened_at {
+            None => false,
+            Some(opened_at) if now < opened_at + self.conf.open_for => true,
+            Some(_) => {
+                // half-open, only one probe at a time
+                let probe_in_flight = inner
+                    .probe_started_at
+                    .map(|started_at| now < started_at + self.conf.open_for)
+                    .unwrap_or(false);
+                if !probe_in_flight {
+                    inner.probe_started_at = Some(now);
+                }
+
+                probe_in_flight
+            }
+        };
+
+        if is_open {
+            Err(BreakerOpen {
+                url: self.url.clone(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn record_at(&self, success: bool, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.opened_at.is_some() {
+            // this is the probe reporting back, or a call which was let
+            // through before the breaker opened, which is as good a probe
+            inner.probe_started_at = None;
+
+            if success {
+                info!("Circuit breaker of '{}' closed", self.url);
+                inner.opened_at = None;
+                inner.outcomes.clear();
+                inner.failures = 0;
+            } else {
+                inner.opened_at = Some(now);
+            }
+
+            return;
+        }
+
+        if inner.outcomes.len() == self.conf.window {
+            if let Some(false) = inner.outcomes.pop_front() {
+                inner.failures -= 1;
+            }
+        }
+        inner.outcomes.push_back(success);
+        if !success {
+            inner.failures += 1;
+        }
+
+        let calls = inner.outcomes.len();
+        if calls >= self.conf.min_calls
+            && inner.failures as f64 >= self.conf.error_rate * calls as f64
+        {
+            warn!(
+                "Circuit breaker of '{}' opened, {} of last {} calls failed",
+                self.url, inner.failures, calls
+            );
+            inner.opened_at = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(
+            "http://localhost",
+            BreakerConf {
+                window: 10,
+                min_calls: 4,
+                error_rate: 0.5,
+                open_for: Duration::from_secs(5),
+            },
+        )
+    }
+
+    #[test]
+    fn it_opens_after_threshold() {
+        let breaker = breaker();
+        let now = Instant::now();
+
+        breaker.record_at(false, now);
+        breaker.record_at(false, now);
+        breaker.record_at(true, now);
+        // not enough calls yet
+        assert_eq!(breaker.state_at(now), BreakerState::Closed);
+
+        breaker.record_at(false, now);
+        assert_eq!(breaker.state_at(now), BreakerState::Open);
+        assert!(breaker.acquire_at(now).is_err());
+    }
+
+    #[test]
+    fn it_probes_when_half_open() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..4 {
+            breaker.record_at(false, now);
+        }
+
+        let later = now + Duration::from_secs(6);
+        assert_eq!(breaker.state_at(later), BreakerState::HalfOpen);
+        assert!(breaker.acquire_at(later).is_ok());
+        // only one probe at a time
+        assert!(breaker.acquire_at(later).is_err());
+
+        // failed probe opens the breaker again
+        breaker.record_at(false, later);
+        assert_eq!(breaker.state_at(later), BreakerState::Open);
+
+        let even_later = later + Duration::from_secs(6);
+        assert!(breaker.acquire_at(even_later).is_ok());
+        breaker.record_at(true, even_later);
+        assert_eq!(breaker.state_at(even_later), BreakerState::Closed);
+        assert!(breaker.acquire_at(even_later).is_ok());
+    }
+
+    #[test]
+    fn it_forgets_old_outcomes() {
+        let breaker = breaker();
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            breaker.record_at(false, now);
+            for _ in 0..5 {
+                breaker.record_at(true, now);
+            }
+        }
+
+        assert_eq!(breaker.state_at(now), BreakerState::Closed);
+    }
+}
@@ -0,0 +1,134 @@
+//! The same tx can be fetched more than once, e.g. when a puller's batch is
+//! rolled back after the txs failed to insert, or when history is reprocessed.
+//! [`TxCache`] keeps raw responses on local disk so that we don't have to ask
+//! the node again.
+//!
+//! Since a tx never changes once it's executed, the cache is content addressed
+//! by digest and never invalidated. Entries are named by the base58 form of
+//! the digest, as it's shown in logs, and sharded into directories by its
+//! first two characters to keep directories small. Base58 is case sensitive,
+//! so is the filesystem of the cache directory expected to be.
+//!
+//! The cache is unbounded, nothing is ever evicted. Entries can be deleted at
+//! any time, e.g. by age with `find -mtime`, a deleted entry is just a miss.
+//! Several processes can share the directory.
+
+use anyhow::{Context, Result};
+use log::warn;
+use misc::sui_sdk::rpc_types::SuiTransactionResponse;
+use misc::Digest;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs;
+
+/// Makes tmp file names unique within the process.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub struct TxCache {
+    dir: PathBuf,
+}
+
+impl TxCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// A broken cache must never fail a fetch, errors are only logged and
+    /// treated as a miss.
+    pub async fn get(&self, digest: &Digest) -> Option<SuiTransactionResponse> {
+        match self.read(digest).await {
+            Ok(tx) => tx,
+            Err(e) => {
+                warn!("Cannot read tx {} from cache: {:#}", digest, e);
+                None
+            }
+        }
+    }
+
+    pub async fn put(&self, digest: &Digest, tx: &SuiTransactionResponse) {
+        if let Err(e) = self.write(digest, tx).await {
+            warn!("Cannot write tx {} to cache: {:#}", digest, e);
+        }
+    }
+
+    async fn read(
+        &self,
+        digest: &Digest,
+    ) -> Result<Option<SuiTransactionResponse>> {
+        let path = self.path(digest);
+        let bytes = match fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(path.display().to_string()),
+        };
+
+        Ok(Some(serde_json::from_slice(&bytes)?))
+    }
+
+    async fn write(
+        &self,
+        digest: &Digest,
+        tx: &SuiTransactionResponse,
+    ) -> Result<()> {
+        let path = self.path(digest);
+        // parent always exists, see `path`
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)
+            .await
+            .with_context(|| dir.display().to_string())?;
+
+        // a reader must never see a half written entry, hence write to a tmp
+        // file first and then atomically move it in place
+        //
+        // the same tx might be written concurrently, by this or another
+        // process, so each writer has a tmp file of its own
+        let tmp_path = path.with_extension(format!(
+            "json.{}.{}.tmp",
+            process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = async {
+            fs::write(&tmp_path, serde_json::to_vec(tx)?)
+                .await
+                .with_context(|| tmp_path.display().to_string())?;
+            fs::rename(&tmp_path, &path)
+                .await
+                .with_context(|| path.display().to_string())
+        }
+        .await;
+        if written.is_err() {
+            // best effort, the tmp file might not even exist
+            fs::remove_file(&tmp_path).await.ok();
+        }
+
+        written
+    }
+
+    fn path(&self, digest: &Digest) -> PathBuf {
+        let name = digest.to_string();
+        // base58 of 32 bytes is never shorter than two characters
+        let shard = &name[..2];
+
+        self.dir.join(shard).join(format!("{}.json", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_names_entries_as_digests_are_logged() {
+        let cache = TxCache::new(PathBuf::from("/cache"));
+        let digest = Digest::new([0xab; Digest::LENGTH]);
+
+        assert_eq!(
+            cache.path(&digest),
+            PathBuf::from(
+                "/cache/CZ/CZ8YUVdk7znjrUmnb5n7kgySk9yRAsQDYmyCxzfSky9t.json"
+            )
+        );
+    }
+}
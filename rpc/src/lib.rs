@@ -1,48 +1,182 @@
-use anyhow::{anyhow, Result};
+mod batch;
+mod breaker;
+mod cache;
+mod limit;
+
+pub use batch::fetch_txs;
+pub use breaker::{BreakerConf, BreakerOpen, BreakerState, CircuitBreaker};
+pub use limit::LimitsConf;
+
+use anyhow::{anyhow, Context, Result};
+use cache::TxCache;
 use futures::Future;
+use jsonrpsee_core::Error as RpcError;
+use limit::Limiter;
 use misc::sui_sdk::{
     rpc_types::SuiTransactionResponse, types::base_types::TransactionDigest,
     SuiClient,
 };
-use misc::{Digest, SeqNum};
-use tokio::time::{sleep, Duration};
+use misc::{env_var, Digest, Retry, RetryPolicy, SeqNum};
+use reqwest::StatusCode;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration, Instant};
 
 /// Unlikely to be useful once Sui is adopted, but in case the network is
 /// idle, how long to wait for next poll.
 const SLEEP_ON_NO_NEW_TXS: Duration = Duration::from_millis(5);
 
+/// Settings of a [`Client`].
+#[derive(Clone, Debug, Default)]
+pub struct ClientConf {
+    pub retry: RetryPolicy,
+    pub breaker: BreakerConf,
+    pub limits: LimitsConf,
+    /// If set, fetched txs are cached in this directory and fetching them
+    /// again doesn't reach the node. The cache is unbounded, see the `cache`
+    /// module.
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl ClientConf {
+    /// Reads env vars prefixed with `RPC_RETRY`, `RPC_BREAKER` and
+    /// `RPC_LIMIT`, see [`RetryPolicy::from_env`], [`BreakerConf::from_env`]
+    /// and [`LimitsConf::from_env`], and optional `RPC_CACHE_DIR`.
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            retry: RetryPolicy::from_env("RPC_RETRY")
+                .context("RPC retry policy")?,
+            breaker: BreakerConf::from_env("RPC_BREAKER")
+                .context("RPC circuit breaker")?,
+            limits: LimitsConf::from_env("RPC_LIMIT").context("RPC limits")?,
+            cache_dir: env_var("RPC_CACHE_DIR")?,
+        })
+    }
+}
+
+/// Connection to a single RPC node. Each call made through it goes through the
+/// endpoint's [`CircuitBreaker`], is rate limited according to its
+/// [`LimitsConf`] and is retried according to its [`RetryPolicy`].
+pub struct Client {
+    url: String,
+    inner: SuiClient,
+    retry: RetryPolicy,
+    breaker: Arc<CircuitBreaker>,
+    limiter: Limiter,
+    /// For requests which [`SuiClient`] doesn't support, see [`fetch_txs`].
+    http: reqwest::Client,
+    cache: Option<TxCache>,
+}
+
+impl Client {
+    pub async fn connect(url: &str, conf: ClientConf) -> Result<Self> {
+        Ok(Self {
+            url: url.to_string(),
+            inner: SuiClient::new_rpc_client(url, None).await?,
+            retry: conf.retry,
+            breaker: Arc::new(CircuitBreaker::new(url, conf.breaker)),
+            limiter: Limiter::new(&conf.limits),
+            http: reqwest::Client::new(),
+            cache: conf.cache_dir.map(TxCache::new),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Shared by all calls made through this client. It's an [`Arc`] so that
+    /// status servers can report its state.
+    pub fn breaker(&self) -> &Arc<CircuitBreaker> {
+        &self.breaker
+    }
+
+    pub(crate) async fn cached_tx(
+        &self,
+        digest: &Digest,
+    ) -> Option<SuiTransactionResponse> {
+        match &self.cache {
+            Some(cache) => cache.get(digest).await,
+            None => None,
+        }
+    }
+
+    pub(crate) async fn cache_tx(
+        &self,
+        digest: &Digest,
+        tx: &SuiTransactionResponse,
+    ) {
+        if let Some(cache) = &self.cache {
+            cache.put(digest, tx).await;
+        }
+    }
+
+    pub(crate) async fn retry<T, F>(&self, job: impl FnMut() -> F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        self.retry_batch(1, job).await
+    }
+
+    /// Like [`Client::retry`] but each attempt carries `requests` requests
+    /// in one round trip, see [`Limiter::acquire_n`].
+    pub(crate) async fn retry_batch<T, F>(
+        &self,
+        requests: usize,
+        mut job: impl FnMut() -> F,
+    ) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        misc::retry(&self.retry, classify, || {
+            let attempt = job();
+            async move {
+                self.breaker.acquire()?;
+                let _permit = self.limiter.acquire_n(requests).await;
+
+                let res = attempt.await;
+                // the node responding with an error is still a responsive node
+                self.breaker.record(match &res {
+                    Ok(_) => true,
+                    Err(e) => classify(e) == Retry::Permanent,
+                });
+
+                res
+            }
+        })
+        .await
+    }
+}
+
 /// Fetches consecutive digests starting from given seq# inclusive. Also returns
-/// the seqnum of the latest digest (last in the vec).
+/// the seqnum of the latest digest (last in the vec) and how long the RPC call
+/// which returned the digests took. Polling doesn't count towards that
+/// latency.
 ///
 /// This fn never returns an empty vector, it keeps polling until new digests
 /// are available.
 ///
-/// Each RPC call is retried a few times with an exponential back-off before
+/// Each RPC call is retried according to the client's [`RetryPolicy`] before
 /// returning an error.
 pub async fn fetch_digests(
-    sui: &SuiClient,
+    sui: &Client,
     start_from_seqnum: SeqNum,
     limit: usize,
-) -> Result<(SeqNum, Vec<Digest>)> {
+) -> Result<(SeqNum, Vec<Digest>, Duration)> {
     let fetch_until_seqnum = start_from_seqnum + limit as u64;
 
     loop {
-        let txs = retry_rpc(move || {
-            // TODO: confirm that we can provide larger tx id than highest
-            // existing and it will gracefully return
-            sui.read_api().get_transactions_in_range(
-                start_from_seqnum,
-                fetch_until_seqnum,
-            )
-        })
-        .await?;
+        let started_at = Instant::now();
+        let txs =
+            fetch_digests_in_range(sui, start_from_seqnum, fetch_until_seqnum)
+                .await?;
+        let latency = started_at.elapsed();
 
         if let Some((seq_num, _)) = txs.last() {
             break Ok((
                 *seq_num,
-                txs.into_iter()
-                    .map(|(_, digest)| digest.to_bytes())
-                    .collect(),
+                txs.into_iter().map(|(_, digest)| digest).collect(),
+                latency,
             ));
         } else {
             sleep(SLEEP_ON_NO_NEW_TXS).await;
@@ -50,39 +184,120 @@ pub async fn fetch_digests(
     }
 }
 
+/// Fetches digests with seq# from `start_from_seqnum` inclusive until
+/// `fetch_until_seqnum` exclusive.
+///
+/// Unlike [`fetch_digests`], this fn does not poll. If the node hasn't got
+/// that far yet, the returned vector is shorter than the range or empty.
+pub async fn fetch_digests_in_range(
+    sui: &Client,
+    start_from_seqnum: SeqNum,
+    fetch_until_seqnum: SeqNum,
+) -> Result<Vec<(SeqNum, Digest)>> {
+    let txs = sui
+        .retry(move || {
+            // TODO: confirm that we can provide larger tx id than highest
+            // existing and it will gracefully return
+            sui.inner.read_api().get_transactions_in_range(
+                start_from_seqnum,
+                fetch_until_seqnum,
+            )
+        })
+        .await?;
+
+    Ok(txs
+        .into_iter()
+        .map(|(seq_num, digest)| (seq_num, digest.into()))
+        .collect())
+}
+
 /// Gets the most recent tx's digest.
-pub async fn latest_digest(sui: &SuiClient) -> Result<Digest> {
-    let txs = retry_rpc(|| sui.read_api().get_recent_transactions(1)).await?;
+pub async fn latest_digest(sui: &Client) -> Result<Digest> {
+    let txs = sui
+        .retry(|| sui.inner.read_api().get_recent_transactions(1))
+        .await?;
 
     txs.into_iter()
         .next()
-        .map(|(_, digest)| digest.to_bytes())
+        .map(|(_, digest)| digest.into())
         .ok_or_else(|| anyhow!("There are no txs known to the node yet"))
 }
 
 /// Returns digest of tx with given seqnum.
-pub async fn digest(sui: &SuiClient, seqnum: SeqNum) -> Result<Option<Digest>> {
-    let txs =
-        retry_rpc(|| sui.read_api().get_transactions_in_range(seqnum, seqnum))
-            .await?;
+pub async fn digest(sui: &Client, seqnum: SeqNum) -> Result<Option<Digest>> {
+    let txs = sui
+        .retry(|| {
+            sui.inner
+                .read_api()
+                .get_transactions_in_range(seqnum, seqnum)
+        })
+        .await?;
+
+    Ok(txs.into_iter().next().map(|(_, digest)| digest.into()))
+}
 
-    Ok(txs.into_iter().next().map(|(_, digest)| digest.to_bytes()))
+/// How many txs the node knows of. This is the seq# the next tx will get.
+pub async fn total_transaction_number(sui: &Client) -> Result<SeqNum> {
+    sui.retry(|| sui.inner.read_api().get_total_transaction_number())
+        .await
 }
 
+/// Checks the client's cache first, if it has one.
 pub async fn fetch_tx(
-    sui: &SuiClient,
-    digest: &[u8],
+    sui: &Client,
+    digest: &Digest,
 ) -> Result<SuiTransactionResponse> {
-    let digest = TransactionDigest::new(digest.try_into()?);
-    retry_rpc(|| sui.read_api().get_transaction(digest)).await
+    if let Some(tx) = sui.cached_tx(digest).await {
+        return Ok(tx);
+    }
+
+    let tx_digest = TransactionDigest::from(*digest);
+    let tx = sui
+        .retry(|| sui.inner.read_api().get_transaction(tx_digest))
+        .await?;
+    sui.cache_tx(digest, &tx).await;
+
+    Ok(tx)
 }
 
-async fn retry_rpc<T, F>(job: impl FnMut() -> F) -> Result<T>
-where
-    F: Future<Output = Result<T>>,
-{
-    // 1st retry after 10ms
-    // 2nd retry after 100ms
-    // 3rd retry after 1s
-    misc::retry(job, 3, 10, 10).await
+/// Transport errors are worth retrying. However, if the node responded with an
+/// error, e.g. because it doesn't know the tx, or with something we cannot
+/// deserialize, then retrying won't help.
+///
+/// Calls rejected by an open [`CircuitBreaker`] aren't retried either, they're
+/// meant to fail fast.
+fn classify(err: &anyhow::Error) -> Retry {
+    for cause in err.chain() {
+        if let Some(rpc_err) = cause.downcast_ref::<RpcError>() {
+            return match rpc_err {
+                RpcError::Call(_)
+                | RpcError::ParseError(_)
+                | RpcError::InvalidResponse(_) => Retry::Permanent,
+                _ => Retry::Transient,
+            };
+        }
+
+        if let Some(http_err) = cause.downcast_ref::<reqwest::Error>() {
+            // the node might be just throttling us
+            let is_client_error = http_err
+                .status()
+                .map(|status| {
+                    status.is_client_error()
+                        && status != StatusCode::TOO_MANY_REQUESTS
+                })
+                .unwrap_or(false);
+
+            return if http_err.is_decode() || is_client_error {
+                Retry::Permanent
+            } else {
+                Retry::Transient
+            };
+        }
+
+        if cause.is::<serde_json::Error>() || cause.is::<BreakerOpen>() {
+            return Retry::Permanent;
+        }
+    }
+
+    Retry::Transient
 }
@@ -0,0 +1,186 @@
+//! Public gateways throttle clients which call them too often. Each
+//! [`crate::Client`] therefore owns a [`Limiter`] which caps how many requests
+//! per second it sends to its endpoint and how many of them are in flight at
+//! once. All calls through the client share it.
+
+use anyhow::{bail, Result};
+use misc::env_var;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::{sleep, Duration};
+
+#[derive(Clone, Debug, Default)]
+pub struct LimitsConf {
+    /// How many requests per second on average are sent to the endpoint. No
+    /// limit if not set.
+    pub requests_per_second: Option<f64>,
+    /// How many requests can be sent at once after a quiet period. Defaults
+    /// to one second worth of requests.
+    pub burst: Option<usize>,
+    /// How many requests can be in flight at once. No limit if not set.
+    pub max_concurrent: Option<usize>,
+}
+
+impl LimitsConf {
+    /// Reads following env vars, none of which is required:
+    /// - `{prefix}_REQUESTS_PER_SECOND`
+    /// - `{prefix}_BURST`
+    /// - `{prefix}_MAX_CONCURRENT`
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        let key = |name: &str| format!("{}_{}", prefix, name);
+
+        let conf = Self {
+            requests_per_second: env_var(&key("REQUESTS_PER_SECOND"))?,
+            burst: env_var(&key("BURST"))?,
+            max_concurrent: env_var(&key("MAX_CONCURRENT"))?,
+        };
+
+        if matches!(conf.requests_per_second, Some(rps) if rps <= 0.0) {
+            bail!("{}_REQUESTS_PER_SECOND must be positive", prefix);
+        }
+        if conf.burst == Some(0) || conf.max_concurrent == Some(0) {
+            bail!(
+                "{}_BURST and {}_MAX_CONCURRENT must be positive",
+                prefix,
+                prefix
+            );
+        }
+
+        Ok(conf)
+    }
+}
+
+pub struct Limiter {
+    bucket: Option<Mutex<TokenBucket>>,
+    concurrency: Option<Semaphore>,
+}
+
+impl Limiter {
+    pub fn new(conf: &LimitsConf) -> Self {
+        Self {
+            bucket: conf.requests_per_second.map(|rate| {
+                let capacity = conf.burst.unwrap_or(rate.ceil() as usize);
+                Mutex::new(TokenBucket::new(rate, capacity, Instant::now()))
+            }),
+            concurrency: conf.max_concurrent.map(Semaphore::new),
+        }
+    }
+
+    /// Waits until `n` requests can be sent in one round trip, e.g. as a
+    /// batch. They count as `n` requests towards the rate limit and as one
+    /// towards the concurrency limit. The returned permit must be held until
+    /// the round trip finishes.
+    pub async fn acquire_n(&self, n: usize) -> Option<SemaphorePermit<'_>> {
+        let permit = match &self.concurrency {
+            // the semaphore is never closed
+            Some(semaphore) => Some(semaphore.acquire().await.unwrap()),
+            None => None,
+        };
+
+        if let Some(bucket) = &self.bucket {
+            loop {
+                // the lock is never held across an await point
+                let taken = bucket.lock().unwrap().take(n, Instant::now());
+                match taken {
+                    Ok(()) => break,
+                    Err(wait) => sleep(wait).await,
+                }
+            }
+        }
+
+        permit
+    }
+}
+
+struct TokenBucket {
+    /// Tokens per second.
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: usize, now: Instant) -> Self {
+        Self {
+            rate,
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refilled_at: now,
+        }
+    }
+
+    /// Takes `n` tokens if there are enough, otherwise returns how long until
+    /// there are.
+    ///
+    /// More tokens than the capacity can never be available at once, so such
+    /// a take waits for a full bucket and leaves it in debt which the
+    /// following takes wait out.
+    fn take(&mut self, n: usize, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate)
+            .min(self.capacity);
+        self.refilled_at = now;
+
+        let needed = (n as f64).min(self.capacity);
+        if self.tokens >= needed {
+            self.tokens -= n as f64;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((needed - self.tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_allows_burst_then_waits() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2, now);
+
+        assert!(bucket.take(1, now).is_ok());
+        assert!(bucket.take(1, now).is_ok());
+
+        let wait = bucket.take(1, now).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(100));
+
+        assert!(bucket.take(1, now + Duration::from_millis(100)).is_ok());
+    }
+
+    #[test]
+    fn it_does_not_refill_past_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2, now);
+
+        let later = now + Duration::from_secs(60);
+        assert!(bucket.take(1, later).is_ok());
+        assert!(bucket.take(1, later).is_ok());
+        assert!(bucket.take(1, later).is_err());
+    }
+
+    #[test]
+    fn it_takes_a_token_per_request_of_batch() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 5, now);
+
+        assert!(bucket.take(3, now).is_ok());
+        let wait = bucket.take(3, now).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(100));
+        assert!(bucket.take(3, now + Duration::from_millis(100)).is_ok());
+    }
+
+    #[test]
+    fn it_lets_batch_larger_than_capacity_through_in_debt() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 5, now);
+
+        // waits for a full bucket only, then owes the rest
+        assert!(bucket.take(20, now).is_ok());
+        let wait = bucket.take(1, now).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(1600));
+    }
+}
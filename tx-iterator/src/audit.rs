@@ -0,0 +1,244 @@
+//! Offline consistency check of the `digests` table against RPC nodes.
+//!
+//! After a failover nothing verifies that every digest observed by every node
+//! ended up in db exactly once. Given a seq# range, the audit fetches digests
+//! of that range from each node and reports
+//! 1. digests observed on some node but missing in db;
+//! 2. digests stored in db within the span of the observed digests but not
+//! observed on any node;
+//! 3. digests which are ordered differently by the first node and the others.
+//!
+//! Optionally, the missing digests are inserted into db.
+//!
+//! # Note
+//! Since each node orders broadcast txs differently, a digest can fall just
+//! outside of the seq# range on one node and inside of it on another. Some
+//! extra digests near the range boundaries are therefore expected.
+
+use crate::prelude::*;
+use std::collections::HashSet;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Seq# to start auditing from, inclusive.
+    #[clap(long)]
+    pub from: SeqNum,
+    /// Seq# to audit until, exclusive.
+    #[clap(long)]
+    pub to: SeqNum,
+    /// Comma separated RPC node urls. Defaults to `SUI_NODE_URL`.
+    #[clap(long, value_delimiter = ',')]
+    pub nodes: Vec<String>,
+    /// Inserts digests missing in db. They are appended in the order in which
+    /// the nodes (first node first) observed them.
+    #[clap(long)]
+    pub backfill: bool,
+}
+
+struct NodeDigests {
+    url: String,
+    digests: Vec<(SeqNum, Digest)>,
+}
+
+pub async fn start(conf: Conf, args: Args) -> Result<()> {
+    if args.from >= args.to {
+        bail!("Empty seq# range {}..{}", args.from, args.to);
+    }
+
+    let urls = if args.nodes.is_empty() {
+        vec![conf.sui_node_url.clone()]
+    } else {
+        args.nodes.clone()
+    };
+
+    let mut nodes = Vec::with_capacity(urls.len());
+    for url in urls {
+        let sui = RpcClient::connect(&url, conf.rpc_client.clone())
+            .await
+            .with_context(|| format!("Cannot connect to node '{}'", url))?;
+        let digests = fetch_node_digests(&conf, &sui, args.from, args.to)
+            .await
+            .with_context(|| format!("Cannot fetch digests from '{}'", url))?;
+        info!(
+            "Node '{}' has {} digests in seq# range {}..{}",
+            url,
+            digests.len(),
+            args.from,
+            args.to
+        );
+
+        nodes.push(NodeDigests { url, digests });
+    }
+
+    // backfilling requires the writer connection
+    let db = if args.backfill || conf.is_leader() {
+        conf.leader_db()?
+    } else {
+        conf.support_db()?
+    };
+
+    // all digests observed on any node in order of first appearance
+    let mut observed_set = HashSet::new();
+    let observed: Vec<Digest> = nodes
+        .iter()
+        .flat_map(|node| node.digests.iter().map(|(_, digest)| digest))
+        .filter(|digest| observed_set.insert(*digest))
+        .copied()
+        .collect();
+
+    // 1.
+    let mut stored = Vec::with_capacity(observed.len());
+    for chunk in observed.chunks(conf.query_tx_digests_batch) {
+        stored.extend(db::select_stored_digests(&db, chunk).await?);
+    }
+    let stored_set: HashSet<_> =
+        stored.iter().map(|(_, digest)| digest).collect();
+    let missing: Vec<_> = observed
+        .iter()
+        .filter(|digest| !stored_set.contains(digest))
+        .copied()
+        .collect();
+    for digest in &missing {
+        warn!("Digest '{}' is missing in db", digest);
+    }
+
+    // 2.
+    let min_id = stored.iter().map(|(id, _)| *id).min();
+    let max_id = stored.iter().map(|(id, _)| *id).max();
+    let extra = if let (Some(min_id), Some(max_id)) = (min_id, max_id) {
+        db::select_digests_in_id_range(&db, min_id, max_id)
+            .await?
+            .into_iter()
+            .filter(|(_, digest)| !observed_set.contains(digest))
+            .collect()
+    } else {
+        vec![]
+    };
+    for (id, digest) in &extra {
+        warn!(
+            "Digest '{}' with id {} is stored in db but was not observed \
+            on any node",
+            digest, id
+        );
+    }
+
+    // 3.
+    if let Some((reference, others)) = nodes.split_first() {
+        for other in others {
+            let differences =
+                ordering_differences(&reference.digests, &other.digests);
+            if let Some((reference_seqnum, other_seqnum)) = differences.first()
+            {
+                warn!(
+                    "Nodes '{}' and '{}' order {} shared digests differently, \
+                    first at seq# {} and {} respectively",
+                    reference.url,
+                    other.url,
+                    differences.len(),
+                    reference_seqnum,
+                    other_seqnum
+                );
+            }
+        }
+    }
+
+    info!(
+        "Audited {} digests: {} missing in db, {} extra in db",
+        observed.len(),
+        missing.len(),
+        extra.len()
+    );
+
+    if missing.is_empty() {
+        Ok(())
+    } else if args.backfill {
+        db::bulk_insert_digests(&db, &missing)
+            .await
+            .context("Cannot backfill missing digests")?;
+        info!("Backfilled {} missing digests", missing.len());
+
+        Ok(())
+    } else {
+        Err(anyhow!("{} digests are missing in db", missing.len()))
+    }
+}
+
+/// Fetches all digests the node has in given range. If the node hasn't got as
+/// far as `to` yet, returns what's available.
+async fn fetch_node_digests(
+    conf: &Conf,
+    sui: &RpcClient,
+    from: SeqNum,
+    to: SeqNum,
+) -> Result<Vec<(SeqNum, Digest)>> {
+    let mut digests = Vec::with_capacity((to - from) as usize);
+
+    let mut fetch_from_seqnum = from;
+    while fetch_from_seqnum < to {
+        let fetch_until_seqnum =
+            to.min(fetch_from_seqnum + conf.fetch_tx_digests_batch as u64);
+        let batch = rpc::fetch_digests_in_range(
+            sui,
+            fetch_from_seqnum,
+            fetch_until_seqnum,
+        )
+        .await?;
+
+        let reached_tip =
+            batch.len() < (fetch_until_seqnum - fetch_from_seqnum) as usize;
+
+        if let Some((latest_seqnum, _)) = batch.last() {
+            fetch_from_seqnum = latest_seqnum + 1;
+            digests.extend(batch);
+        }
+
+        if reached_tip {
+            warn!(
+                "Node only has digests until seq# {}, auditing until there",
+                fetch_from_seqnum
+            );
+            break;
+        }
+    }
+
+    Ok(digests)
+}
+
+/// Compares the order of digests observed by both nodes. Returns the seq#s at
+/// which the two sequences of shared digests disagree.
+fn ordering_differences(
+    a: &[(SeqNum, Digest)],
+    b: &[(SeqNum, Digest)],
+) -> Vec<(SeqNum, SeqNum)> {
+    let a_digests: HashSet<_> = a.iter().map(|(_, digest)| digest).collect();
+    let b_digests: HashSet<_> = b.iter().map(|(_, digest)| digest).collect();
+
+    let a_shared = a.iter().filter(|(_, digest)| b_digests.contains(digest));
+    let b_shared = b.iter().filter(|(_, digest)| a_digests.contains(digest));
+
+    a_shared
+        .zip(b_shared)
+        .filter(|((_, a_digest), (_, b_digest))| a_digest != b_digest)
+        .map(|((a_seqnum, _), (b_seqnum, _))| (*a_seqnum, *b_seqnum))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn d(byte: u8) -> Digest {
+        Digest::new([byte; Digest::LENGTH])
+    }
+
+    #[test]
+    fn it_finds_ordering_differences() {
+        let a = vec![(1, d(1)), (2, d(2)), (3, d(3)), (4, d(4))];
+        let b = vec![(1, d(1)), (2, d(3)), (3, d(5)), (4, d(2))];
+
+        // shared digests are 1, 2, 3 and the node b swapped 2 and 3
+        assert_eq!(ordering_differences(&a, &b), vec![(2, 2), (3, 4)]);
+        assert_eq!(ordering_differences(&a, &a), vec![]);
+        assert_eq!(ordering_differences(&a, &[]), vec![]);
+    }
+}
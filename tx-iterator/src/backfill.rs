@@ -0,0 +1,92 @@
+//! Catching up on history without running a leader.
+//!
+//! Backfill fetches digests of a bounded seq# range from the RPC node in
+//! parallel chunks and inserts those which are not stored yet into the writer
+//! db. The chunks are inserted in order of their seq#s.
+//!
+//! Backfill does not touch leadership nor does it run the status server, so
+//! it's safe to run it next to a live leader.
+
+use crate::prelude::*;
+use futures::{stream, StreamExt};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Seq# to start backfilling from, inclusive.
+    #[clap(long)]
+    pub from: SeqNum,
+    /// Seq# to backfill until, exclusive.
+    #[clap(long)]
+    pub to: SeqNum,
+    /// How many digests are fetched from RPC in each call. Defaults to
+    /// `FETCH_TX_DIGESTS_BATCH`.
+    #[clap(long)]
+    pub chunk_size: Option<usize>,
+    /// How many RPC calls are in flight at once.
+    #[clap(long, default_value_t = consts::defaults::BACKFILL_PARALLELISM)]
+    pub parallelism: usize,
+}
+
+pub async fn start(conf: Conf, args: Args) -> Result<()> {
+    if args.from >= args.to {
+        bail!("Empty seq# range {}..{}", args.from, args.to);
+    }
+    let chunk_size = args.chunk_size.unwrap_or(conf.fetch_tx_digests_batch);
+    if chunk_size == 0 || args.parallelism == 0 {
+        bail!("Chunk size and parallelism must be positive");
+    }
+
+    let sui = conf.rpc().await?;
+    let db = conf.leader_db()?;
+
+    let chunks = (args.from..args.to)
+        .step_by(chunk_size)
+        .map(|from| (from, args.to.min(from + chunk_size as u64)));
+
+    // `buffered` keeps the order of chunks while fetching them in parallel
+    let sui = &sui;
+    let mut fetched_chunks = stream::iter(chunks)
+        .map(|(from, to)| async move {
+            let digests = rpc::fetch_digests_in_range(sui, from, to)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Cannot fetch digests in seq# range {}..{}",
+                        from, to
+                    )
+                })?;
+
+            Ok::<_, anyhow::Error>((from, to, digests))
+        })
+        .buffered(args.parallelism);
+
+    while let Some(chunk) = fetched_chunks.next().await {
+        let (from, to, digests) = chunk?;
+        let reached_tip = digests.len() < (to - from) as usize;
+
+        if !digests.is_empty() {
+            let digests: Vec<_> =
+                digests.into_iter().map(|(_, digest)| digest).collect();
+
+            // digests which are already stored are skipped
+            db::bulk_insert_digests(&db, &digests).await.with_context(
+                || {
+                    format!(
+                        "Cannot insert digests in seq# range {}..{}",
+                        from, to
+                    )
+                },
+            )?;
+        }
+
+        if reached_tip {
+            // the following chunks are past the node's tip too
+            warn!("Node has no more digests after seq# range {}..{}", from, to);
+            break;
+        }
+
+        info!("Backfilled seq# range {}..{}", from, to);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! The number of digests we ask RPC for in one call is a trade-off.
+//!
+//! When we're catching up, large batches mean fewer round trips and higher
+//! throughput. Near the tip, large batches don't help because there are only a
+//! few new digests per poll, and they make each call slower.
+//!
+//! [`AdaptiveBatch`] doubles the batch size when RPC keeps returning full
+//! pages and halves it when the pages aren't full or when the calls are slow.
+
+use crate::prelude::*;
+use futures::Future;
+use tokio::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct AdaptiveBatch {
+    size: usize,
+    min: usize,
+    max: usize,
+    latency_threshold: Duration,
+}
+
+impl AdaptiveBatch {
+    pub fn new(conf: &Conf) -> Self {
+        Self {
+            size: conf.fetch_tx_digests_batch,
+            min: conf.min_fetch_tx_digests_batch,
+            max: conf.max_fetch_tx_digests_batch,
+            latency_threshold: conf.fetch_tx_digests_latency_threshold,
+        }
+    }
+
+    /// How many digests to ask for in the next call.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Adjusts the batch size given how many digests a call which asked for
+    /// `requested` digests returned and how long it took.
+    ///
+    /// With several windows in flight, `requested` can differ from the
+    /// current [`AdaptiveBatch::size`] because the size changed after the
+    /// window had been scheduled. A stale window therefore never undoes a
+    /// change made by a newer one, nor compounds it.
+    pub fn observe(
+        &mut self,
+        requested: usize,
+        fetched: usize,
+        latency: Duration,
+    ) {
+        let size = if latency > self.latency_threshold {
+            // the node is struggling, go easy on it
+            self.size.min(requested / 2)
+        } else if fetched >= requested {
+            // full page means there are more digests waiting, we're catching
+            // up
+            self.size.max(requested * 2)
+        } else if fetched < requested / 2 {
+            // near the tip
+            self.size.min(requested / 2)
+        } else {
+            self.size
+        };
+
+        let size = size.clamp(self.min, self.max);
+        if size != self.size {
+            info!("Changing RPC batch size from {} to {}", self.size, size);
+            self.size = size;
+        }
+    }
+}
+
+/// Measures how long it takes for given future to resolve.
+pub async fn timed<T>(job: impl Future<Output = T>) -> (T, Duration) {
+    let started_at = Instant::now();
+    let output = job.await;
+
+    (output, started_at.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch() -> AdaptiveBatch {
+        AdaptiveBatch {
+            size: 128,
+            min: 16,
+            max: 512,
+            latency_threshold: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn it_grows_on_full_pages() {
+        let mut batch = batch();
+        let fast = Duration::from_millis(10);
+
+        batch.observe(batch.size(), 128, fast);
+        assert_eq!(batch.size(), 256);
+
+        batch.observe(batch.size(), 256, fast);
+        assert_eq!(batch.size(), 512);
+
+        batch.observe(batch.size(), 512, fast);
+        assert_eq!(batch.size(), 512);
+    }
+
+    #[test]
+    fn it_shrinks_near_tip() {
+        let mut batch = batch();
+        let fast = Duration::from_millis(10);
+
+        batch.observe(batch.size(), 100, fast);
+        assert_eq!(batch.size(), 128);
+
+        batch.observe(batch.size(), 1, fast);
+        assert_eq!(batch.size(), 64);
+
+        for _ in 0..10 {
+            batch.observe(batch.size(), 1, fast);
+        }
+        assert_eq!(batch.size(), 16);
+    }
+
+    #[test]
+    fn it_shrinks_on_high_latency() {
+        let mut batch = batch();
+
+        batch.observe(batch.size(), 128, Duration::from_secs(2));
+        assert_eq!(batch.size(), 64);
+    }
+
+    #[test]
+    fn it_compares_against_requested_size_of_in_flight_windows() {
+        let mut batch = batch();
+        let fast = Duration::from_millis(10);
+
+        // four windows of 128 were scheduled, all of them come back full
+        for _ in 0..4 {
+            batch.observe(128, 128, fast);
+        }
+        assert_eq!(batch.size(), 256);
+
+        // windows scheduled after the growth come back full too
+        batch.observe(256, 256, fast);
+        assert_eq!(batch.size(), 512);
+
+        // older windows which were full don't shrink the batch even though
+        // they are below the current size
+        batch.observe(256, 256, fast);
+        batch.observe(128, 128, fast);
+        assert_eq!(batch.size(), 512);
+
+        // the tip was reached, shrink relative to what the window asked for
+        batch.observe(128, 60, fast);
+        assert_eq!(batch.size(), 64);
+    }
+}
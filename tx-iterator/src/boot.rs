@@ -1,5 +1,5 @@
-//! When the service boots it needs to determine which db to connect to based
-//! on its [`Role`] - [`Conf::db_conn_to_boot_with`].
+//! When the service boots it needs to determine which storage to connect to
+//! based on its [`Role`] - [`Conf::storage_to_boot_with`].
 //!
 //! Then it needs to determine which seq# to start iterating from -
 //! [`find_seqnum_to_start_iterating_from`].
@@ -15,24 +15,11 @@ impl Conf {
     /// Over the lifetime of the service this may not reflect the right
     /// connection anymore: the service could have been promoted from support to
     /// lead.
-    pub async fn db_conn_to_boot_with(&self) -> Result<DbClient> {
-        let tls = tokio_postgres::NoTls;
-        let (client, conn) = match &self.spawned_as {
-            Role::Leader => {
-                tokio_postgres::connect(&self.writer_conn_conf, tls).await?
-            }
-            Role::Support { db_conn_conf } => {
-                tokio_postgres::connect(db_conn_conf, tls).await?
-            }
-        };
-
-        tokio::spawn(async move {
-            if let Err(e) = conn.await {
-                error!("booted db connection error: {}", e);
-            }
-        });
-
-        Ok(client)
+    pub fn storage_to_boot_with(&self) -> Result<Box<dyn Storage>> {
+        match &self.spawned_as {
+            Role::Leader => self.leader_storage(),
+            Role::Support { .. } => self.support_storage(),
+        }
     }
 }
 
@@ -41,8 +28,8 @@ impl Conf {
 /// with http server which runs in this service. This is used by supervisor.
 pub async fn find_seqnum_to_start_iterating_from(
     conf: &Conf,
-    _db: &DbClient,
-    sui: &SuiClient,
+    _db: &dyn Storage,
+    sui: &RpcClient,
 ) -> Result<AtomicU64> {
     let start_iterating_from_seqnum = if let Some(seqnum) = conf.initial_seq_num
     {
@@ -68,7 +55,7 @@ pub async fn find_seqnum_to_start_iterating_from(
                 "Sui SDK does not yet support mapping from digest to seq#"
             );
         } else {
-            sui.read_api().get_total_transaction_number().await?
+            rpc::total_transaction_number(sui).await?
         }
     };
 
@@ -3,22 +3,49 @@ use std::{env, net::SocketAddr};
 use tokio::time::Duration;
 
 pub mod consts {
-    use tokio::time::Duration;
-
-    /// How many digests are fetched from RPC in each call and then persisted to
-    /// the db.
-    /// OPTIMIZE:
-    pub const FETCH_TX_DIGESTS_BATCH: usize = 128;
-
-    /// How many digests are fetched from db in each select.
-    pub const QUERY_TX_DIGESTS_BATCH: usize = 1_024;
+    /// See [`crate::nodes::Nodes::failover`].
+    pub const FAILOVER_MAX_REWIND_WINDOWS: usize = 16;
 
     pub mod defaults {
-        use super::*;
+        use tokio::time::Duration;
 
         /// See [`crate::conf::Conf::investigate_if_tx_only_observed_on_rpc_for`].
         pub const INVESTIGATE_IF_TX_ONLY_OBSERVED_ON_RPC_FOR: Duration =
             Duration::from_secs(30);
+
+        /// How many RPC calls are in flight at once when backfilling history.
+        pub const BACKFILL_PARALLELISM: usize = 8;
+
+        /// How many digests are archived in one statement.
+        pub const RETENTION_CHUNK_SIZE: i64 = 10_000;
+
+        /// See [`crate::conf::Conf::fetch_tx_digests_batch`].
+        pub const FETCH_TX_DIGESTS_BATCH: usize = 128;
+
+        /// See [`crate::conf::Conf::min_fetch_tx_digests_batch`].
+        pub const MIN_FETCH_TX_DIGESTS_BATCH: usize = 16;
+
+        /// See [`crate::conf::Conf::max_fetch_tx_digests_batch`].
+        pub const MAX_FETCH_TX_DIGESTS_BATCH: usize = 4_096;
+
+        /// See [`crate::conf::Conf::fetch_tx_digests_latency_threshold`].
+        pub const FETCH_TX_DIGESTS_LATENCY_THRESHOLD: Duration =
+            Duration::from_millis(1_000);
+
+        /// See [`crate::conf::Conf::failover_rewind_seqnums`].
+        pub const FAILOVER_REWIND_SEQNUMS: u64 = 1_024;
+
+        /// See [`crate::conf::Conf::rpc_pipeline_depth`].
+        pub const RPC_PIPELINE_DEPTH: usize = 4;
+
+        /// See [`crate::conf::Conf::query_tx_digests_batch`].
+        pub const QUERY_TX_DIGESTS_BATCH: usize = 1_024;
+
+        /// See [`crate::conf::Conf::db_pool_size`].
+        pub const DB_POOL_SIZE: usize = 2;
+
+        /// See [`crate::conf::Conf::shutdown_grace_period`].
+        pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
     }
 }
 
@@ -49,6 +76,21 @@ pub struct Conf {
     pub writer_conn_conf: String,
     /// Gateway RPC, e.g. `https://gateway.devnet.sui.io:443`.
     pub sui_node_url: String,
+    /// If RPC calls to [`Conf::sui_node_url`] keep failing, we switch to
+    /// these urls in order, see [`crate::nodes`].
+    pub fallback_sui_node_urls: Vec<String>,
+    /// After switching to a fallback node, we walk back from where we got to
+    /// on the previous node in windows of this many seq#s, until all digests
+    /// of a window are stored. This covers the difference in ordering between
+    /// the two nodes, see [`crate::nodes`].
+    ///
+    /// Defaults to [`consts::defaults::FAILOVER_REWIND_SEQNUMS`].
+    pub failover_rewind_seqnums: u64,
+    /// How RPC calls are retried before we consider the node failed and when
+    /// the node's circuit breaker opens.
+    ///
+    /// See [`rpc::ClientConf::from_env`].
+    pub rpc_client: rpc::ClientConf,
     /// Defaults to the seq# of the latest stored tx in db.
     /// This would be problematic if there was just a single tx-iterator.
     /// If leader's RPC became unavailable, we wouldn't have a way to tell
@@ -72,6 +114,52 @@ pub struct Conf {
     /// # Note
     /// This settings is irrelevant for leader node.
     pub investigate_if_tx_only_observed_on_rpc_for: Duration,
+    /// How many digests are fetched from RPC in the first call. The following
+    /// calls adapt the batch size, see [`crate::batch::AdaptiveBatch`].
+    ///
+    /// Defaults to [`consts::defaults::FETCH_TX_DIGESTS_BATCH`].
+    pub fetch_tx_digests_batch: usize,
+    /// The adaptive RPC batch never shrinks below this size.
+    ///
+    /// Defaults to [`consts::defaults::MIN_FETCH_TX_DIGESTS_BATCH`].
+    pub min_fetch_tx_digests_batch: usize,
+    /// The adaptive RPC batch never grows above this size.
+    ///
+    /// Defaults to [`consts::defaults::MAX_FETCH_TX_DIGESTS_BATCH`].
+    pub max_fetch_tx_digests_batch: usize,
+    /// If fetching a batch of digests from RPC takes longer than this, the
+    /// adaptive batch shrinks.
+    ///
+    /// Defaults to [`consts::defaults::FETCH_TX_DIGESTS_LATENCY_THRESHOLD`].
+    pub fetch_tx_digests_latency_threshold: Duration,
+    /// How many RPC calls over consecutive seq# windows the leader keeps in
+    /// flight while catching up. Near the tip there's always just one.
+    ///
+    /// Defaults to [`consts::defaults::RPC_PIPELINE_DEPTH`].
+    pub rpc_pipeline_depth: usize,
+    /// How many digests are fetched from db in each select.
+    ///
+    /// Defaults to [`consts::defaults::QUERY_TX_DIGESTS_BATCH`].
+    pub query_tx_digests_batch: usize,
+    /// How many connections each db pool opens at most.
+    ///
+    /// Defaults to [`consts::defaults::DB_POOL_SIZE`].
+    pub db_pool_size: usize,
+    /// Applies to both the writer and the support connections.
+    ///
+    /// See [`db::TlsConf::from_env`].
+    pub db_tls: db::TlsConf,
+    /// Which storage the leader writes digests into, see
+    /// [`db::StorageBackend::from_env`]. In memory, nothing is shared with
+    /// supports, hence only a leader can use it. The subcommands always use
+    /// the db.
+    pub storage: db::StorageBackend,
+    /// After the iterator stopped on SIGTERM or SIGINT, the status server
+    /// keeps running for this long so that the supervisor can read the final
+    /// seq#.
+    ///
+    /// Defaults to [`consts::defaults::SHUTDOWN_GRACE_PERIOD`].
+    pub shutdown_grace_period: Duration,
 }
 
 impl Conf {
@@ -79,6 +167,27 @@ impl Conf {
         let sui_node_url = env::var("SUI_NODE_URL").context("Sui Node URL")?;
         info!("RPC url: {}", sui_node_url);
 
+        let fallback_sui_node_urls: Vec<_> = env::var("FALLBACK_SUI_NODE_URLS")
+            .map(|urls| {
+                urls.split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        info!("Fallback RPC urls: {:?}", fallback_sui_node_urls);
+
+        let failover_rewind_seqnums = env::var("FAILOVER_REWIND_SEQNUMS")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()
+            .context("Failover rewind seq#s")?
+            .unwrap_or(consts::defaults::FAILOVER_REWIND_SEQNUMS);
+
+        let rpc_client = rpc::ClientConf::from_env()?;
+        info!("RPC client: {:?}", rpc_client);
+
         let writer_conn_conf =
             env::var("WRITER_CONN_CONF").context("Writer DB URL")?;
 
@@ -117,13 +226,114 @@ impl Conf {
             investigate_if_tx_only_observed_on_rpc_for
         );
 
+        let fetch_tx_digests_batch = env_usize(
+            "FETCH_TX_DIGESTS_BATCH",
+            consts::defaults::FETCH_TX_DIGESTS_BATCH,
+        )?;
+        let min_fetch_tx_digests_batch = env_usize(
+            "MIN_FETCH_TX_DIGESTS_BATCH",
+            consts::defaults::MIN_FETCH_TX_DIGESTS_BATCH,
+        )?;
+        let max_fetch_tx_digests_batch = env_usize(
+            "MAX_FETCH_TX_DIGESTS_BATCH",
+            consts::defaults::MAX_FETCH_TX_DIGESTS_BATCH,
+        )?;
+        if min_fetch_tx_digests_batch == 0
+            || min_fetch_tx_digests_batch > fetch_tx_digests_batch
+            || fetch_tx_digests_batch > max_fetch_tx_digests_batch
+        {
+            bail!(
+                "RPC batch sizes must satisfy 0 < min <= initial <= max, \
+                got {} <= {} <= {}",
+                min_fetch_tx_digests_batch,
+                fetch_tx_digests_batch,
+                max_fetch_tx_digests_batch
+            );
+        }
+        info!(
+            "RPC batch size {} within [{}, {}]",
+            fetch_tx_digests_batch,
+            min_fetch_tx_digests_batch,
+            max_fetch_tx_digests_batch
+        );
+
+        let fetch_tx_digests_latency_threshold =
+            env::var("FETCH_TX_DIGESTS_LATENCY_THRESHOLD_MS")
+                .ok()
+                .map(|s| s.parse::<u64>())
+                .transpose()?
+                .map(Duration::from_millis)
+                .unwrap_or(
+                    consts::defaults::FETCH_TX_DIGESTS_LATENCY_THRESHOLD,
+                );
+        info!(
+            "RPC batch latency threshold {:?}",
+            fetch_tx_digests_latency_threshold
+        );
+
+        let rpc_pipeline_depth = env_usize(
+            "RPC_PIPELINE_DEPTH",
+            consts::defaults::RPC_PIPELINE_DEPTH,
+        )?;
+        if rpc_pipeline_depth == 0 {
+            bail!("RPC pipeline depth must be positive");
+        }
+        info!("RPC pipeline depth {}", rpc_pipeline_depth);
+
+        let query_tx_digests_batch = env_usize(
+            "QUERY_TX_DIGESTS_BATCH",
+            consts::defaults::QUERY_TX_DIGESTS_BATCH,
+        )?;
+        if query_tx_digests_batch == 0 {
+            bail!("Db batch size must be positive");
+        }
+        info!("Db batch size {}", query_tx_digests_batch);
+
+        let db_pool_size =
+            env_usize("DB_POOL_SIZE", consts::defaults::DB_POOL_SIZE)?;
+        if db_pool_size == 0 {
+            bail!("Db pool size must be positive");
+        }
+
+        let db_tls = db::TlsConf::from_env("DB_TLS").context("Db TLS")?;
+        info!("Db TLS: {:?}", db_tls);
+
+        let storage = db::StorageBackend::from_env("STORAGE")?;
+        if storage == db::StorageBackend::Memory
+            && matches!(role, Role::Support { .. })
+        {
+            bail!("Support cannot read digests from memory storage");
+        }
+        info!("Storage: {:?}", storage);
+
+        let shutdown_grace_period = env::var("SHUTDOWN_GRACE_PERIOD_MS")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()
+            .context("Shutdown grace period")?
+            .map(Duration::from_millis)
+            .unwrap_or(consts::defaults::SHUTDOWN_GRACE_PERIOD);
+
         Ok(Self {
             spawned_as: role,
             writer_conn_conf,
             sui_node_url,
+            fallback_sui_node_urls,
+            failover_rewind_seqnums,
+            rpc_client,
             investigate_if_tx_only_observed_on_rpc_for,
             http_addr,
             initial_seq_num,
+            fetch_tx_digests_batch,
+            min_fetch_tx_digests_batch,
+            max_fetch_tx_digests_batch,
+            fetch_tx_digests_latency_threshold,
+            rpc_pipeline_depth,
+            query_tx_digests_batch,
+            db_pool_size,
+            db_tls,
+            storage,
+            shutdown_grace_period,
         })
     }
 
@@ -131,20 +341,43 @@ impl Conf {
         matches!(self.spawned_as, Role::Leader)
     }
 
-    pub async fn rpc(&self) -> Result<SuiClient> {
-        SuiClient::new_rpc_client(&self.sui_node_url, None).await
+    pub async fn rpc(&self) -> Result<RpcClient> {
+        RpcClient::connect(&self.sui_node_url, self.rpc_client.clone()).await
     }
 
-    pub async fn leader_db(&self) -> Result<DbClient> {
-        db::connect(&self.writer_conn_conf).await
+    pub fn leader_db(&self) -> Result<DbPool> {
+        db::pool(&self.writer_conn_conf, self.db_pool_size, &self.db_tls)
     }
 
-    pub async fn support_db(&self) -> Result<DbClient> {
+    pub fn leader_storage(&self) -> Result<Box<dyn Storage>> {
+        Ok(match self.storage {
+            db::StorageBackend::Postgres => {
+                Box::new(db::PgStorage::new(self.leader_db()?))
+            }
+            db::StorageBackend::Memory => Box::new(db::MemoryStorage::new()),
+        })
+    }
+
+    pub fn support_storage(&self) -> Result<Box<dyn Storage>> {
+        // memory storage is rejected for supports
+        Ok(Box::new(db::PgStorage::new(self.support_db()?)))
+    }
+
+    pub fn support_db(&self) -> Result<DbPool> {
         match self.spawned_as {
             Role::Leader => Err(anyhow!("Not a support node")),
             Role::Support { ref db_conn_conf } => {
-                db::connect(db_conn_conf).await
+                db::pool(db_conn_conf, self.db_pool_size, &self.db_tls)
             }
         }
     }
 }
+
+fn env_usize(key: &str, default: usize) -> Result<usize> {
+    Ok(env::var(key)
+        .ok()
+        .map(|s| s.parse::<usize>())
+        .transpose()
+        .with_context(|| format!("Invalid {}", key))?
+        .unwrap_or(default))
+}
@@ -1,20 +1,31 @@
 //! HTTP server is used by supervisor to inspect tx-iterator inner state.
 
 use crate::prelude::*;
+use rpc::CircuitBreaker;
 use std::sync::{
     atomic::{AtomicBool, AtomicU64, Ordering},
-    Arc,
+    Arc, RwLock,
 };
 use warp::Filter;
 
 pub struct StatusReport {
     pub is_leader: AtomicBool,
     pub next_fetch_from_seqnum: AtomicU64,
+    /// The seq# is only meaningful in the context of the node which is being
+    /// iterated. This changes on failover, see [`crate::nodes`].
+    pub sui_node_url: RwLock<String>,
+    /// Breaker of the node we're currently connected to.
+    pub breaker: RwLock<Arc<CircuitBreaker>>,
+    /// Set on shutdown once the iterator stopped and the seq# is final.
+    pub stopped: AtomicBool,
 }
 
 /// Blocking operation which starts http server with paths:
 /// 1. GET /leader => prints "true"/"false"
 /// 2. GET /seqnum => prints a number in the body
+/// 3. GET /node => prints the url of the RPC node the seq# belongs to
+/// 4. GET /breaker => prints "closed"/"open"/"half-open"
+/// 5. GET /stopped => prints "true"/"false"
 ///
 /// # Note
 /// We use [`Ordering::SeqCst`] to read the values are performance here is not
@@ -27,11 +38,33 @@ pub async fn start(conf: Conf, status: Arc<StatusReport>) {
     });
 
     // 2.
+    let status_prime = Arc::clone(&status);
     let seqnum = warp::path("seqnum").map(move || {
-        format!("{}", status.next_fetch_from_seqnum.load(Ordering::SeqCst))
+        format!(
+            "{}",
+            status_prime.next_fetch_from_seqnum.load(Ordering::SeqCst)
+        )
+    });
+
+    // 3.
+    let status_prime = Arc::clone(&status);
+    let node = warp::path("node").map(move || {
+        // the lock is never held across an await point, it cannot be poisoned
+        status_prime.sui_node_url.read().unwrap().clone()
     });
 
-    let routes = warp::get().and(seqnum.or(leader));
+    // 4.
+    let status_prime = Arc::clone(&status);
+    let breaker = warp::path("breaker").map(move || {
+        format!("{}", status_prime.breaker.read().unwrap().state())
+    });
+
+    // 5.
+    let stopped = warp::path("stopped")
+        .map(move || format!("{}", status.stopped.load(Ordering::SeqCst)));
+
+    let routes =
+        warp::get().and(seqnum.or(leader).or(node).or(breaker).or(stopped));
 
     warp::serve(routes).run(conf.http_addr).await;
 }
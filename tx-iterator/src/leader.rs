@@ -1,74 +1,138 @@
 use crate::http::StatusReport;
+use crate::nodes::Nodes;
+use crate::pipeline::Pipeline;
 use crate::prelude::*;
+use misc::Shutdown;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
+/// Why [`iterate`] stopped iterating.
+enum Interrupted {
+    /// RPC calls failed even after retries.
+    RpcFailed(anyhow::Error),
+    /// All fetched digests are persisted and the status reports the seq# to
+    /// continue from.
+    Shutdown,
+}
+
 /// Starts polling RPC for new digests and persists them into db.
 ///
-/// RPC errors are retried based on implementation in [`crate::rpc`] module.
-/// If the retries failed, this fn returns an error.
+/// RPC errors are retried based on implementation in the [`rpc`] crate.
+/// If the retries failed, we fail over to the next RPC node, see
+/// [`crate::nodes`]. If there's none left, this fn returns an error.
 ///
-/// Db error logged, then a new connection is created. If the new connection
-/// does not work, this fn returns an error.
+/// Db error logged, then the insert is retried once. [`db::PgStorage`] retries
+/// over a connection from the pool, which replaces broken connections. If the
+/// retry fails too, this fn returns an error.
 ///
 /// This fn fetches from RPC and inserts into db in parallel. While prev
 /// iteration is being persisted, new digests are being fetched.
+///
+/// The number of digests fetched in each call adapts to whether we're catching
+/// up or following the tip, see [`crate::batch::AdaptiveBatch`]. When catching
+/// up, several RPC calls are in flight at once, see [`Pipeline`].
+///
+/// Returns once shutdown is requested and the digests which were already
+/// fetched are persisted.
 pub async fn start(
     conf: Conf,
-    sui: SuiClient,
-    mut db: DbClient,
+    mut nodes: Nodes,
+    db: Box<dyn Storage>,
     status: Arc<StatusReport>,
+    shutdown: Shutdown,
 ) -> Result<()> {
+    loop {
+        match iterate(&conf, nodes.sui(), &*db, &status, &shutdown).await? {
+            Interrupted::RpcFailed(rpc_err) => {
+                warn!("RPC node '{}' failed: {:?}", nodes.url(), rpc_err);
+                nodes.failover(&conf, &*db, &status).await?;
+            }
+            Interrupted::Shutdown => break Ok(()),
+        }
+    }
+}
+
+async fn iterate(
+    conf: &Conf,
+    sui: &RpcClient,
+    db: &dyn Storage,
+    status: &StatusReport,
+    shutdown: &Shutdown,
+) -> Result<Interrupted> {
+    let mut pipeline = Pipeline::new(
+        conf,
+        sui,
+        // since this operation happens only once per node, it's easier not
+        // having to think about ordering
+        status.next_fetch_from_seqnum.load(Ordering::SeqCst),
+    );
+
     // fetches the first batch and from here on the loop writes to these two
     // variables
     //
     // we do it this way to parallelize rpc and db calls
-    let (mut fetch_from_seqnum, mut digests) = rpc::fetch_digests(
-        &sui,
-        // since this operation happens only once on boot, it's easier not
-        // having to think about ordering
-        status.next_fetch_from_seqnum.load(Ordering::SeqCst),
-        consts::FETCH_TX_DIGESTS_BATCH,
-    )
-    .await?;
+    let (largest_seqnum, mut digests) = tokio::select! {
+        next = pipeline.next_digests() => match next {
+            Ok(next) => next,
+            Err(e) => return Ok(Interrupted::RpcFailed(e)),
+        },
+        _ = shutdown.requested() => return Ok(Interrupted::Shutdown),
+    };
+    // the seq# right after the digests which are about to be persisted
+    let mut fetch_from_seqnum = largest_seqnum + 1;
 
     loop {
         assert!(!digests.is_empty());
 
         // insert previous iteration's digests into db and fetch new digests
-        let (db_call, rpc_call) = tokio::join!(
-            db::insert_digests(&db, &digests),
-            rpc::fetch_digests(
-                &sui,
-                fetch_from_seqnum,
-                consts::FETCH_TX_DIGESTS_BATCH
-            )
-        );
+        //
+        // on shutdown, we stop fetching but the insert always finishes
+        let (db_call, rpc_call) =
+            tokio::join!(db.insert_digests(&digests), async {
+                tokio::select! {
+                    next = pipeline.next_digests() => Some(next),
+                    _ = shutdown.requested() => None,
+                }
+            });
 
-        // try rebuilding connection and inserting again
+        // try inserting again, the pool replaces the connection if it broke
         if let Err(db_err) = db_call {
             warn!(
-                "Failed to insert digests starting from seq# '{}' into db: {}",
-                fetch_from_seqnum, db_err
+                "Failed to insert digests up to seq# '{}' into db: {}",
+                fetch_from_seqnum - 1,
+                db_err
             );
 
-            db = conf
-                .leader_db()
-                .await
-                .context("Cannot revive db connection")?;
-
-            db::insert_digests(&db, &digests)
+            db.insert_digests(&digests)
                 .await
                 .context("Retrying inserting digests failed")?;
         }
 
-        let (next_largest_seqnum, next_digests) =
-            rpc_call.with_context(|| {
-                format!(
+        let rpc_call = match rpc_call {
+            Some(rpc_call) => rpc_call,
+            None => {
+                // this is a one-time occurrence, no need for optimization
+                status
+                    .next_fetch_from_seqnum
+                    .store(fetch_from_seqnum, Ordering::SeqCst);
+                info!(
+                    "Stopped iterating, next seq# to fetch is {}",
+                    fetch_from_seqnum
+                );
+
+                return Ok(Interrupted::Shutdown);
+            }
+        };
+
+        let (next_largest_seqnum, next_digests) = match rpc_call {
+            Ok(next) => next,
+            Err(e) => {
+                return Ok(Interrupted::RpcFailed(e.context(format!(
                     "Cannot fetch next batch of digests starting from '{}'",
                     fetch_from_seqnum,
-                )
-            })?;
+                ))))
+            }
+        };
 
         // these digests are persisted in the next loop iteration
         digests = next_digests;
@@ -1,5 +1,11 @@
 // Ubiquitously used types
 mod prelude;
+// Offline consistency check of stored digests against RPC nodes
+mod audit;
+// Bounded catch up on historical seq# ranges
+mod backfill;
+// Adapting the number of digests fetched from RPC in one call
+mod batch;
 // Methods relevant for startup
 mod boot;
 // Service configuration from env
@@ -8,12 +14,42 @@ mod conf;
 mod http;
 // Polling and persisting digests
 mod leader;
+// Failing over between RPC nodes
+mod nodes;
+// Keeping several RPC calls in flight while catching up
+mod pipeline;
+// Archiving old digests
+mod retention;
 // Polling digests from RPC and db, validating them
 mod support;
 
 use crate::prelude::*;
+use clap::{Parser, Subcommand};
 use conf::Conf;
-use std::sync::{atomic::AtomicBool, Arc};
+use misc::Shutdown;
+use nodes::Nodes;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::time::sleep;
+
+/// Without a subcommand, the service iterates the tip of the chain as a leader
+/// or a support depending on env.
+#[derive(Parser)]
+#[clap(version, about)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compares digests stored in db with digests on RPC nodes.
+    Audit(audit::Args),
+    /// Inserts digests of a historical seq# range which are not stored yet.
+    Backfill(backfill::Args),
+    /// Archives old digests which no consumer needs anymore.
+    Retention(retention::Args),
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -21,26 +57,58 @@ async fn main() -> Result<()> {
 
     env_logger::init(); // set up with env RUST_LOG
 
+    let cli = Cli::parse();
+
     let conf = Conf::from_env()?;
 
-    let db = conf.db_conn_to_boot_with().await?;
-    let sui = conf.rpc().await?;
+    match cli.command {
+        Some(Command::Audit(args)) => return audit::start(conf, args).await,
+        Some(Command::Backfill(args)) => {
+            return backfill::start(conf, args).await
+        }
+        Some(Command::Retention(args)) => {
+            return retention::start(conf, args).await
+        }
+        None => (),
+    };
+
+    let shutdown = Shutdown::listen()?;
+
+    let db = conf.storage_to_boot_with()?;
+    let nodes = Nodes::connect(&conf).await?;
 
     // prepares some state which is shared with the http server to allow
     // supervisor to inspect what's going on
     let status = Arc::new(http::StatusReport {
         is_leader: AtomicBool::new(conf.is_leader()),
         next_fetch_from_seqnum: boot::find_seqnum_to_start_iterating_from(
-            &conf, &db, &sui,
+            &conf,
+            &*db,
+            nodes.sui(),
         )
         .await?,
+        sui_node_url: RwLock::new(nodes.url().to_string()),
+        breaker: RwLock::new(Arc::clone(nodes.sui().breaker())),
+        stopped: AtomicBool::new(false),
     });
 
     tokio::spawn(http::start(conf.clone(), Arc::clone(&status)));
 
+    let grace_period = conf.shutdown_grace_period;
     if conf.is_leader() {
-        leader::start(conf, sui, db, status).await
+        leader::start(conf, nodes, db, Arc::clone(&status), shutdown).await?;
     } else {
-        support::start(conf, sui, db, status).await
+        support::start(conf, nodes, db, Arc::clone(&status), shutdown).await?;
     }
+
+    // we only get here on shutdown, errors return early
+    status.stopped.store(true, Ordering::SeqCst);
+    info!(
+        "Stopped at seq# {}, exiting in {:?}",
+        status.next_fetch_from_seqnum.load(Ordering::SeqCst),
+        grace_period
+    );
+    sleep(grace_period).await;
+
+    Ok(())
 }
@@ -0,0 +1,168 @@
+//! A tx-iterator is bound to [`Conf::sui_node_url`]. When RPC calls keep
+//! failing even after retries, it switches to the next url of
+//! [`Conf::fallback_sui_node_urls`] instead of exiting.
+//!
+//! Since each node has its own ordering of broadcast txs, the seq# we've
+//! iterated up to on the previous node does not map to the same position on
+//! the next one. After switching, we reconcile the new node's digests with the
+//! db, see [`reconcile`], and continue from the lowest seq# whose digest is
+//! not stored. Digests which are already stored are then skipped on insert.
+
+use crate::http::StatusReport;
+use crate::prelude::*;
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+pub struct Nodes {
+    /// The primary url first, then the fallbacks in order of priority.
+    urls: Vec<String>,
+    /// Index into `urls` of the node we're connected to.
+    current: usize,
+    sui: RpcClient,
+}
+
+impl Nodes {
+    /// Connects to the first node of the list which is available.
+    pub async fn connect(conf: &Conf) -> Result<Self> {
+        let urls: Vec<_> = std::iter::once(conf.sui_node_url.clone())
+            .chain(conf.fallback_sui_node_urls.iter().cloned())
+            .collect();
+
+        let (current, sui) = connect_from(conf, &urls, 0).await?;
+
+        Ok(Self { urls, current, sui })
+    }
+
+    pub fn sui(&self) -> &RpcClient {
+        &self.sui
+    }
+
+    pub fn url(&self) -> &str {
+        &self.urls[self.current]
+    }
+
+    /// Switches to the next available node in order of priority. Errors if
+    /// there is none left.
+    ///
+    /// The status is updated with the new node and the seq# of the new node to
+    /// iterate from, which is also returned.
+    pub async fn failover(
+        &mut self,
+        conf: &Conf,
+        db: &dyn Storage,
+        status: &StatusReport,
+    ) -> Result<SeqNum> {
+        let (current, sui) = connect_from(conf, &self.urls, self.current + 1)
+            .await
+            .with_context(|| {
+                format!("No fallback node after '{}'", self.url())
+            })?;
+        info!(
+            "Failing over from '{}' to '{}'",
+            self.url(),
+            self.urls[current]
+        );
+
+        self.current = current;
+        self.sui = sui;
+
+        // the lock is never held across an await point, it cannot be poisoned
+        *status.sui_node_url.write().unwrap() = self.url().to_string();
+        *status.breaker.write().unwrap() = Arc::clone(self.sui.breaker());
+
+        // this is a one-time occurrence, no need for optimization
+        let o = Ordering::SeqCst;
+        let fetch_from_seqnum = reconcile(
+            conf,
+            &self.sui,
+            db,
+            status.next_fetch_from_seqnum.load(o),
+        )
+        .await
+        .with_context(|| {
+            format!("Cannot reconcile node '{}' with db", self.url())
+        })?;
+        info!(
+            "Continuing from seq# {} on '{}'",
+            fetch_from_seqnum,
+            self.url()
+        );
+        status.next_fetch_from_seqnum.store(fetch_from_seqnum, o);
+
+        Ok(fetch_from_seqnum)
+    }
+}
+
+/// Given the seq# we got to on the previous node, returns the lowest seq# on
+/// the new node whose digest is not stored.
+///
+/// Walks back from given seq# in windows of [`Conf::failover_rewind_seqnums`]
+/// until a window whose digests are all stored. Archived digests look as if
+/// they weren't stored, hence the walk gives up after
+/// [`consts::FAILOVER_MAX_REWIND_WINDOWS`] windows.
+async fn reconcile(
+    conf: &Conf,
+    sui: &RpcClient,
+    db: &dyn Storage,
+    seqnum: SeqNum,
+) -> Result<SeqNum> {
+    // the new node might not have gotten as far as the previous one
+    let mut fetch_from_seqnum =
+        seqnum.min(rpc::total_transaction_number(sui).await?);
+    let window = conf.failover_rewind_seqnums;
+    if window == 0 {
+        return Ok(fetch_from_seqnum);
+    }
+
+    let mut until = fetch_from_seqnum;
+    for _ in 0..consts::FAILOVER_MAX_REWIND_WINDOWS {
+        if until == 0 {
+            return Ok(fetch_from_seqnum);
+        }
+        let from = until.saturating_sub(window);
+
+        let fetched = rpc::fetch_digests_in_range(sui, from, until).await?;
+        let digests: Vec<_> =
+            fetched.iter().map(|(_, digest)| *digest).collect();
+        let stored: HashSet<_> = db
+            .select_stored_digests(&digests)
+            .await?
+            .into_iter()
+            .map(|(_, digest)| digest)
+            .collect();
+
+        match fetched.iter().find(|(_, digest)| !stored.contains(digest)) {
+            Some((missing_seqnum, _)) => fetch_from_seqnum = *missing_seqnum,
+            None => return Ok(fetch_from_seqnum),
+        }
+        until = from;
+    }
+
+    warn!(
+        "Digests before seq# {} might be missing, \
+        not rewinding further than {} windows",
+        fetch_from_seqnum,
+        consts::FAILOVER_MAX_REWIND_WINDOWS
+    );
+
+    Ok(fetch_from_seqnum)
+}
+
+async fn connect_from(
+    conf: &Conf,
+    urls: &[String],
+    from: usize,
+) -> Result<(usize, RpcClient)> {
+    for (index, url) in urls.iter().enumerate().skip(from) {
+        match RpcClient::connect(url, conf.rpc_client.clone()).await {
+            Ok(sui) => return Ok((index, sui)),
+            Err(e) => warn!("Cannot connect to node '{}': {}", url, e),
+        }
+    }
+
+    Err(anyhow!(
+        "Cannot connect to any of nodes {:?}",
+        &urls[from..]
+    ))
+}
@@ -0,0 +1,140 @@
+//! During catch-up the leader is bound by the latency of a single RPC call.
+//!
+//! [`Pipeline`] keeps up to [`Conf::rpc_pipeline_depth`] calls in flight over
+//! consecutive seq# windows and yields their digests in order of seq#.
+//! Once a window comes back without being full, we've reached the tip. The
+//! windows past it are discarded and the pipeline falls back to a single
+//! polling call at a time until RPC returns a full page again.
+
+use crate::batch::{timed, AdaptiveBatch};
+use crate::prelude::*;
+use futures::future::BoxFuture;
+use futures::stream::FuturesOrdered;
+use futures::{FutureExt, StreamExt};
+use tokio::time::Duration;
+
+type Window<'a> =
+    BoxFuture<'a, (SeqNum, SeqNum, Result<Vec<(SeqNum, Digest)>>, Duration)>;
+
+pub struct Pipeline<'a> {
+    sui: &'a RpcClient,
+    depth: usize,
+    batch: AdaptiveBatch,
+    /// The seq# of the first digest not yet returned by
+    /// [`Pipeline::next_digests`].
+    fetch_from_seqnum: SeqNum,
+    /// Where the next scheduled window starts.
+    next_window_from_seqnum: SeqNum,
+    /// Ordered by seq#, only non-empty when catching up.
+    in_flight: FuturesOrdered<Window<'a>>,
+    catching_up: bool,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(
+        conf: &Conf,
+        sui: &'a RpcClient,
+        fetch_from_seqnum: SeqNum,
+    ) -> Self {
+        Self {
+            sui,
+            depth: conf.rpc_pipeline_depth,
+            batch: AdaptiveBatch::new(conf),
+            fetch_from_seqnum,
+            next_window_from_seqnum: fetch_from_seqnum,
+            in_flight: FuturesOrdered::new(),
+            // we don't know yet, the first full page tells us
+            catching_up: false,
+        }
+    }
+
+    /// Returns the next consecutive digests and the seq# of the last of them.
+    ///
+    /// Same as with [`rpc::fetch_digests`], the returned vector is never
+    /// empty.
+    pub async fn next_digests(&mut self) -> Result<(SeqNum, Vec<Digest>)> {
+        loop {
+            if !self.catching_up {
+                return self.next_digests_near_tip().await;
+            }
+
+            while self.in_flight.len() < self.depth {
+                self.schedule_window();
+            }
+
+            // safe to unwrap bcs we've just scheduled windows
+            let (from, until, fetched, latency) =
+                self.in_flight.next().await.unwrap();
+            let txs = match fetched {
+                Ok(txs) => txs,
+                Err(e) => {
+                    // the failed window must be fetched again
+                    self.stop_catching_up();
+                    return Err(e);
+                }
+            };
+            self.batch
+                .observe((until - from) as usize, txs.len(), latency);
+
+            let is_full = txs.len() as u64 >= until - from;
+            if is_full {
+                self.fetch_from_seqnum = until;
+            } else {
+                self.fetch_from_seqnum =
+                    txs.last().map(|(seqnum, _)| seqnum + 1).unwrap_or(from);
+
+                // we've reached the tip, any window scheduled past this one
+                // could be missing digests between the two
+                self.stop_catching_up();
+            }
+
+            if let Some((latest_seqnum, _)) = txs.last() {
+                return Ok((
+                    *latest_seqnum,
+                    txs.into_iter().map(|(_, digest)| digest).collect(),
+                ));
+            }
+        }
+    }
+
+    /// Single polling call, the pre-pipeline behaviour.
+    async fn next_digests_near_tip(&mut self) -> Result<(SeqNum, Vec<Digest>)> {
+        let size = self.batch.size();
+        // the latency excludes polling for new digests at the tip
+        let (latest_seqnum, digests, latency) =
+            rpc::fetch_digests(self.sui, self.fetch_from_seqnum, size).await?;
+        self.batch.observe(size, digests.len(), latency);
+
+        self.fetch_from_seqnum = latest_seqnum + 1;
+        self.next_window_from_seqnum = self.fetch_from_seqnum;
+        if digests.len() >= size && self.depth > 1 {
+            info!("Catching up from seq# {}", self.fetch_from_seqnum);
+            self.catching_up = true;
+        }
+
+        Ok((latest_seqnum, digests))
+    }
+
+    fn stop_catching_up(&mut self) {
+        self.in_flight = FuturesOrdered::new();
+        self.catching_up = false;
+        self.next_window_from_seqnum = self.fetch_from_seqnum;
+    }
+
+    fn schedule_window(&mut self) {
+        let sui = self.sui;
+        let from = self.next_window_from_seqnum;
+        let until = from + self.batch.size() as u64;
+        self.next_window_from_seqnum = until;
+
+        self.in_flight.push_back(
+            async move {
+                let (fetched, latency) =
+                    timed(rpc::fetch_digests_in_range(sui, from, until)).await;
+
+                (from, until, fetched, latency)
+            }
+            .boxed(),
+        );
+    }
+}
@@ -1,6 +1,6 @@
 pub use crate::conf::{consts, Conf};
 pub use anyhow::{anyhow, bail, Context, Result};
+pub use db::{Pool as DbPool, Storage};
 pub use log::{error, info, warn};
-pub use misc::sui_sdk::SuiClient;
 pub use misc::{Digest, SeqNum};
-pub use tokio_postgres::Client as DbClient;
+pub use rpc::Client as RpcClient;
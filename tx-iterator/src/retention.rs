@@ -0,0 +1,84 @@
+//! Moving old digests out of the `digests` table, see [`db::archive`].
+//!
+//! The `digests` table only grows, while the leader and supports only ever
+//! read its tip. Retention archives digests in chunks, oldest first, and
+//! never those which
+//! 1. are among the latest `--keep-latest` digests. Supports read digests
+//! since the one at the seq# they start from, and the leader re-inserts
+//! digests after failing over to another node. Keep enough digests to cover
+//! both, hence `--keep-latest` must be at least `FAILOVER_REWIND_SEQNUMS`
+//! plus `MAX_FETCH_TX_DIGESTS_BATCH`;
+//! 2. haven't been enqueued or finished by all consumers, e.g. the puller,
+//! according to their enqueue cursors, watermarks and pending jobs.
+//!
+//! We don't store when a digest was observed, so retention is driven by ids.
+//! Nor do we store which seq# a support starts from, so keeping its starting
+//! digest is a heuristic: it holds as long as the support doesn't lag more
+//! than `--keep-latest` digests behind the leader.
+
+use crate::prelude::*;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// How many digests with the highest ids are never archived.
+    #[clap(long)]
+    pub keep_latest: i64,
+    /// How many digests are moved in one statement.
+    #[clap(long, default_value_t = consts::defaults::RETENTION_CHUNK_SIZE)]
+    pub chunk_size: i64,
+    /// Only prints up to which digest id would be archived.
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+pub async fn start(conf: Conf, args: Args) -> Result<()> {
+    if args.chunk_size <= 0 {
+        bail!("Chunk size must be positive");
+    }
+    // a node we fail over to rewinds this many seq#s and then fetches a
+    // batch, its digests must still be in the table to be recognized
+    let min_keep_latest =
+        conf.failover_rewind_seqnums + conf.max_fetch_tx_digests_batch as u64;
+    if args.keep_latest < 0 || (args.keep_latest as u64) < min_keep_latest {
+        bail!(
+            "Keep latest must be at least {} (FAILOVER_REWIND_SEQNUMS + \
+            MAX_FETCH_TX_DIGESTS_BATCH), got {}",
+            min_keep_latest,
+            args.keep_latest
+        );
+    }
+
+    let db = conf.leader_db()?;
+
+    let until_id =
+        match db::archive::select_archivable_until(&db, args.keep_latest)
+            .await?
+        {
+            Some(until_id) => until_id,
+            None => {
+                info!(
+                    "No digests or no consumer watermarks, nothing to archive"
+                );
+                return Ok(());
+            }
+        };
+
+    if args.dry_run {
+        info!("Would archive digests with id up to {}", until_id);
+        return Ok(());
+    }
+
+    let mut archived = 0;
+    loop {
+        let moved =
+            db::archive::archive_digests_until(&db, until_id, args.chunk_size)
+                .await?;
+        archived += moved;
+        if moved < args.chunk_size as u64 {
+            break;
+        }
+    }
+    info!("Archived {} digests with id up to {}", archived, until_id);
+
+    Ok(())
+}
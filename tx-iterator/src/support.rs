@@ -12,9 +12,12 @@
 //! 3. FIFO queue of RPC digests with timestamp of when we observed them. This
 //! is always a subset of the hashmap 2.
 
+use crate::batch::AdaptiveBatch;
 use crate::http::StatusReport;
 use crate::leader;
+use crate::nodes::Nodes;
 use crate::prelude::*;
+use misc::Shutdown;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -25,75 +28,117 @@ use tokio::time::Instant;
 ///
 /// If the support observes discrepancy which is not fixed over some period of
 /// time, then it assumes the leader role itself.
+///
+/// Support doesn't write anything, so it returns as soon as shutdown is
+/// requested, unless it's being promoted already.
 pub async fn start(
     conf: Conf,
-    sui: SuiClient,
-    mut db: DbClient,
+    mut nodes: Nodes,
+    db: Box<dyn Storage>,
     status: Arc<StatusReport>,
+    shutdown: Shutdown,
 ) -> Result<()> {
-    let fetch_from_seqnum =
+    let mut fetch_from_seqnum =
         status.next_fetch_from_seqnum.load(Ordering::SeqCst);
 
     // latest_db_digest will be mutated in the loop
     let (mut latest_db_digest, initial_db_only_digests) =
-        initial_db_digests(&sui, &db, fetch_from_seqnum).await?;
+        initial_db_digests(&conf, nodes.sui(), &*db, fetch_from_seqnum).await?;
 
     // 1. hashset of db digests not yet observed on RPC
     let mut db_only_digests: HashSet<_> =
         initial_db_only_digests.into_iter().collect();
     // 2. hashmap of RPC digests to seqnums not yet observed in db
     let mut rpc_only_digests =
-        HashMap::with_capacity(consts::FETCH_TX_DIGESTS_BATCH as usize * 4);
+        HashMap::with_capacity(conf.fetch_tx_digests_batch * 4);
     // 3. FIFO queue of RPC digests with timestamp of when we observed them
     let mut rpc_only_digests_timestamps =
         VecDeque::with_capacity(rpc_only_digests.capacity());
 
+    let mut batch = AdaptiveBatch::new(&conf);
+
     loop {
         // OPTIMIZE: measure which of the two is bottleneck, if db we can skip
         // the call every nth iteration or if there hasn't been anything new
         // in the past call
-        let (db_call, rpc_call) = tokio::join!(
-            select_digests_since_exclusive_with_retry(
-                &conf,
-                &mut db,
-                &latest_db_digest,
-            ),
-            rpc::fetch_digests(
-                &sui,
-                fetch_from_seqnum,
-                consts::FETCH_TX_DIGESTS_BATCH
-            ),
-        );
+        let requested = batch.size();
+        let calls = tokio::select! {
+            calls = async {
+                tokio::join!(
+                    select_digests_since_exclusive_with_retry(
+                        &conf,
+                        &*db,
+                        &latest_db_digest,
+                    ),
+                    rpc::fetch_digests(
+                        nodes.sui(),
+                        fetch_from_seqnum,
+                        requested,
+                    ),
+                )
+            } => calls,
+            _ = shutdown.requested() => {
+                info!("Stopped supporting");
+                return Ok(());
+            }
+        };
+        let (db_call, rpc_call) = calls;
 
         let new_db_digests = db_call?;
-        let (latest_seqnum, new_rpc_digests) = rpc_call?;
 
-        if let Some(latest) = new_db_digests.last().cloned() {
+        if let Some(latest) = new_db_digests.last().copied() {
             // if there are some new digests...
 
             latest_db_digest = latest;
             db_only_digests.extend(new_db_digests.into_iter());
         }
 
+        let (latest_seqnum, new_rpc_digests, rpc_latency) = match rpc_call {
+            Ok(fetched) => fetched,
+            Err(rpc_err) => {
+                warn!("RPC node '{}' failed: {:?}", nodes.url(), rpc_err);
+
+                fetch_from_seqnum =
+                    nodes.failover(&conf, &*db, &status).await?;
+
+                // seq#s of the previous node don't map to the new one, we
+                // start over with the new node only
+                rpc_only_digests.clear();
+                rpc_only_digests_timestamps.clear();
+                // the digest right before is stored, see the failover
+                let (latest, initial_db_only_digests) = initial_db_digests(
+                    &conf,
+                    nodes.sui(),
+                    &*db,
+                    fetch_from_seqnum.saturating_sub(1),
+                )
+                .await?;
+                latest_db_digest = latest;
+                db_only_digests = initial_db_only_digests.into_iter().collect();
+                continue;
+            }
+        };
+        batch.observe(requested, new_rpc_digests.len(), rpc_latency);
+
         for (seqnum, digest) in
-            (fetch_from_seqnum..latest_seqnum).zip(new_rpc_digests)
+            (fetch_from_seqnum..=latest_seqnum).zip(new_rpc_digests)
         {
             let is_in_db = db_only_digests.remove(&digest);
             if !is_in_db {
                 // digest not observed in db, we are yet to see it persisted by
                 // the leader
 
-                rpc_only_digests_timestamps
-                    .push_back((Instant::now(), digest.clone()));
+                rpc_only_digests_timestamps.push_back((Instant::now(), digest));
                 rpc_only_digests.insert(digest, seqnum);
             }
         }
+        fetch_from_seqnum = latest_seqnum + 1;
 
         if let Promote::Yes {
             start_leader_from_seqnum,
         } = pop_observed_digests(
             &conf,
-            &db,
+            &*db,
             &mut rpc_only_digests,
             &mut rpc_only_digests_timestamps,
         )
@@ -131,16 +176,15 @@ pub async fn start(
     // then it's likely that our data structure grew large, so avoid OOM
     info!(
         "Promoting support of node '{}' to leader. It had stored {} db digests.",
-        conf.sui_node_url,
+        nodes.url(),
         db_only_digests.len()
     );
     drop(db_only_digests);
 
     // promote db collection
     let db = conf
-        .leader_db()
-        .await
-        .context("Cannot start writer db connection")?;
+        .leader_storage()
+        .context("Cannot start writer db pool")?;
 
     // iterate rpc_only_digests_timestamps and insert that to db
     // in the same order those which are not there yet according to our state
@@ -152,7 +196,7 @@ pub async fn start(
     if let Some(latest_digest) = digests_not_observed_in_db.first() {
         info!(
             "There have been {} digests observed on RPC \
-            but not in db starting with '{:?}'. Inserting them into db.",
+            but not in db starting with '{}'. Inserting them into db.",
             digests_not_observed_in_db.len(),
             latest_digest
         );
@@ -161,9 +205,8 @@ pub async fn start(
             rpc_only_digests.get(latest_digest).copied().unwrap();
         drop(rpc_only_digests); // same reason as drop above
 
-        // TODO: could `digests_not_observed_in_db` be too large one time
-        // insert?
-        db::insert_digests(&db, &digests_not_observed_in_db)
+        // there can be lots of them if the leader has been down for a while
+        db.bulk_insert_digests(&digests_not_observed_in_db)
             .await
             .context("Cannot insert remaining db-unobserved digests")?;
 
@@ -174,7 +217,7 @@ pub async fn start(
             .store(latest_seqnum + 1, Ordering::SeqCst);
     }
 
-    leader::start(conf, sui, db, status).await
+    leader::start(conf, nodes, db, status, shutdown).await
 }
 
 enum Promote {
@@ -186,11 +229,11 @@ enum Promote {
 /// added to the db.
 ///
 /// If it takes longer than
-/// [`consts::INVESTIGATE_IF_TX_ONLY_OBSERVED_ON_RPC_FOR`] to add txs to the db,
+/// [`Conf::investigate_if_tx_only_observed_on_rpc_for`] to add txs to the db,
 /// begin procedure to become a leader.
 async fn pop_observed_digests(
     conf: &Conf,
-    db: &DbClient,
+    db: &dyn Storage,
     rpc_only_digests: &mut HashMap<Digest, SeqNum>,
     rpc_only_digests_timestamps: &mut VecDeque<(Instant, Digest)>,
 ) -> Result<Promote> {
@@ -202,7 +245,7 @@ async fn pop_observed_digests(
         } else if Instant::now().duration_since(*timestamp)
             > conf.investigate_if_tx_only_observed_on_rpc_for
         {
-            if db::has_digest(db, digest).await? {
+            if db.has_digest(digest).await? {
                 // this is an unlikely but conceivable scenario:
                 //
                 // we start fetching from digest0, observe digest1 but
@@ -239,8 +282,9 @@ async fn pop_observed_digests(
 }
 
 async fn initial_db_digests(
-    sui: &SuiClient,
-    db: &DbClient,
+    conf: &Conf,
+    sui: &RpcClient,
+    db: &dyn Storage,
     fetch_from_seqnum: SeqNum,
 ) -> Result<(Digest, Vec<Digest>)> {
     let fetch_from_digest =
@@ -258,52 +302,44 @@ async fn initial_db_digests(
             rpc::latest_digest(sui).await?
         };
 
-    let db_only_digests = db::select_digests_since_inclusive(
-        db,
-        &fetch_from_digest,
-        consts::QUERY_TX_DIGESTS_BATCH,
-    )
-    .await?;
+    let db_only_digests = db
+        .select_digests_since_inclusive(
+            &fetch_from_digest,
+            conf.query_tx_digests_batch,
+        )
+        .await?;
 
     let latest_db_digest =
-        db_only_digests.last().cloned().unwrap_or(fetch_from_digest);
+        db_only_digests.last().copied().unwrap_or(fetch_from_digest);
 
     Ok((latest_db_digest, db_only_digests))
 }
 
-/// Since the state we've built here is valuable, let's attempt to
-/// rebuild the db conn before crashing the service.
+/// Since the state we've built here is valuable, let's retry once before
+/// crashing the service. The pool replaces the connection if it broke.
 async fn select_digests_since_exclusive_with_retry(
     conf: &Conf,
-    db: &mut DbClient,
+    db: &dyn Storage,
     latest_db_digest: &Digest,
 ) -> Result<Vec<Digest>> {
-    let db_call = db::select_digests_since_exclusive(
-        db,
-        latest_db_digest,
-        consts::QUERY_TX_DIGESTS_BATCH,
-    )
-    .await;
-
-    // since the state we've built here is valuable, let's attempt to
-    // rebuild the db conn before crashing the service
+    let db_call = db
+        .select_digests_since_exclusive(
+            latest_db_digest,
+            conf.query_tx_digests_batch,
+        )
+        .await;
+
     match db_call {
         ok @ Ok(_) => ok,
         Err(db_err) => {
             warn!(
-                "Failed to select digests since '{:?}' from db: {}",
+                "Failed to select digests since '{}' from db: {}",
                 latest_db_digest, db_err
             );
 
-            *db = conf
-                .support_db()
-                .await
-                .context("Cannot revive db connection")?;
-
-            db::select_digests_since_exclusive(
-                db,
+            db.select_digests_since_exclusive(
                 latest_db_digest,
-                consts::QUERY_TX_DIGESTS_BATCH,
+                conf.query_tx_digests_batch,
             )
             .await
         }
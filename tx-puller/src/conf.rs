@@ -1,9 +1,28 @@
 use crate::prelude::*;
-use std::env;
+use std::{env, net::SocketAddr, process, sync::Arc, time::Duration};
 
 pub mod consts {
+    use std::time::Duration;
+
+    /// The puller's jobs in [`db::queue`].
+    pub const JOB_KIND: &str = "pull_txs";
+
+    /// How long to wait before polling the queue again if it's empty.
+    pub const SLEEP_ON_NO_JOBS: Duration = Duration::from_millis(500);
+
+    /// How many txs the export reads from db at once.
+    pub const EXPORT_PAGE_SIZE: i64 = 1_000;
+
     pub mod defaults {
+        use super::Duration;
+
         pub const BATCH_SIZE: usize = 10;
+        pub const MAX_JOB_ATTEMPTS: i32 = 5;
+        /// One connection for the batches, one for the watermark.
+        pub const DB_POOL_SIZE: usize = 2;
+        pub const JOB_LEASE: Duration = Duration::from_secs(60);
+        /// How many orders each exported file spans.
+        pub const EXPORT_FILE_ORDERS: i64 = 100_000;
     }
 }
 
@@ -16,6 +35,29 @@ pub struct Conf {
     pub sui_node_url: String,
     /// How many txs to fetch from DB at once.
     pub batch_size: usize,
+    /// How many connections the db pool opens at most.
+    pub db_pool_size: usize,
+    /// See [`db::TlsConf::from_env`].
+    pub db_tls: db::TlsConf,
+    /// Identifies this puller's claims in the job queue, hence it must be
+    /// unique across pullers. Defaults to `tx-puller-{hostname}-{pid}-{random}`
+    /// since pids repeat across hosts and containers.
+    pub worker_id: String,
+    /// After this many failed attempts to fetch a tx, its job is given up on.
+    pub max_job_attempts: i32,
+    /// How long claimed jobs are leased to this puller. If the puller doesn't
+    /// report back by then, the jobs are claimed by another one.
+    pub job_lease: Duration,
+    /// How RPC calls are retried before the digest is left for a later
+    /// batch and when the node's circuit breaker opens.
+    ///
+    /// See [`rpc::ClientConf::from_env`].
+    pub rpc_client: rpc::ClientConf,
+    /// If set, the puller advances the watermark of processed digests this
+    /// often, see the `watermark` module.
+    pub watermark_interval: Option<Duration>,
+    /// If set, the status server binds to this address.
+    pub http_addr: Option<SocketAddr>,
 }
 
 impl Conf {
@@ -33,18 +75,96 @@ impl Conf {
             .unwrap_or(consts::defaults::BATCH_SIZE);
         info!("Batch size: {}", batch_size);
 
+        let db_pool_size = env::var("DB_POOL_SIZE")
+            .ok()
+            .map(|s| s.parse::<usize>())
+            .transpose()
+            .context("Invalid db pool size")?
+            .unwrap_or(consts::defaults::DB_POOL_SIZE);
+        if db_pool_size == 0 {
+            bail!("Db pool size must be positive");
+        }
+
+        let db_tls = db::TlsConf::from_env("DB_TLS").context("Db TLS")?;
+        info!("Db TLS: {:?}", db_tls);
+
+        // nothing would ever feed digests to an in-memory storage in this
+        // process, the puller would sit idle forever
+        if let db::StorageBackend::Memory =
+            db::StorageBackend::from_env("STORAGE")?
+        {
+            bail!("The puller only supports postgres storage");
+        }
+
+        let worker_id = env::var("WORKER_ID").unwrap_or_else(|_| {
+            format!(
+                "tx-puller-{}-{}-{:08x}",
+                env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_string()),
+                process::id(),
+                rand::random::<u32>()
+            )
+        });
+        info!("Worker id: {}", worker_id);
+
+        let max_job_attempts = env::var("MAX_JOB_ATTEMPTS")
+            .ok()
+            .map(|s| s.parse::<i32>())
+            .transpose()
+            .context("Invalid max job attempts")?
+            .unwrap_or(consts::defaults::MAX_JOB_ATTEMPTS);
+
+        let job_lease = env::var("JOB_LEASE_MS")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()
+            .context("Invalid job lease")?
+            .map(Duration::from_millis)
+            .unwrap_or(consts::defaults::JOB_LEASE);
+        info!("Job lease: {:?}", job_lease);
+
+        let rpc_client = rpc::ClientConf::from_env()?;
+        info!("RPC client: {:?}", rpc_client);
+
+        let watermark_interval = env::var("WATERMARK_INTERVAL_MS")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()
+            .context("Invalid watermark interval")?
+            .map(Duration::from_millis);
+        if watermark_interval == Some(Duration::ZERO) {
+            bail!("Watermark interval must be positive");
+        }
+
+        let http_addr = env::var("HTTP_ADDR")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("Invalid http addr")?;
+
         Ok(Self {
             sui_node_url,
             writer_conn_conf,
             batch_size,
+            db_pool_size,
+            db_tls,
+            worker_id,
+            max_job_attempts,
+            job_lease,
+            rpc_client,
+            watermark_interval,
+            http_addr,
         })
     }
 
-    pub async fn rpc(&self) -> Result<SuiClient> {
-        SuiClient::new_rpc_client(&self.sui_node_url, None).await
+    pub async fn rpc(&self) -> Result<RpcClient> {
+        RpcClient::connect(&self.sui_node_url, self.rpc_client.clone()).await
+    }
+
+    pub fn db(&self) -> Result<DbPool> {
+        db::pool(&self.writer_conn_conf, self.db_pool_size, &self.db_tls)
     }
 
-    pub async fn db(&self) -> Result<DbClient> {
-        db::connect(&self.writer_conn_conf).await
+    pub fn storage(&self) -> Result<Arc<dyn Storage>> {
+        Ok(Arc::new(db::PgStorage::new(self.db()?)))
     }
 }
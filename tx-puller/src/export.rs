@@ -0,0 +1,273 @@
+//! Exporting txs for analytics outside of Postgres.
+//!
+//! Txs are read by ranges of their order and written into NDJSON files, one
+//! file per range of `--file-orders` orders. Files are grouped into
+//! directories by the partition of `txs` they come from, see
+//! [`db::partitions`]. Each line holds a tx with its data decoded from
+//! bincode.
+//!
+//! Txs are only final up to the puller's watermark, see the `watermark`
+//! module. We only export ranges which are entirely below the watermark, hence
+//! the latest txs are exported once the watermark passes the end of their
+//! range.
+//!
+//! A manifest in the export directory lists the written files and the order up
+//! to which txs are exported. Running the export again with the same directory
+//! continues from there.
+
+use crate::conf::{consts, Conf};
+use crate::prelude::*;
+use db::partitions::TXS_PARTITION_SIZE;
+use misc::sui_sdk::rpc_types::SuiTransactionResponse;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Directory which the files and the manifest are written to.
+    #[clap(long)]
+    pub dir: PathBuf,
+    /// Order to start exporting from, inclusive. Ignored if the manifest says
+    /// that we've got further already.
+    #[clap(long, default_value_t = 0)]
+    pub from_order: i64,
+    /// Order to export until, inclusive. Defaults to the watermark, and is
+    /// never above it.
+    #[clap(long)]
+    pub to_order: Option<i64>,
+    /// How many orders each file spans. Must divide the size of a partition.
+    #[clap(long, default_value_t = consts::defaults::EXPORT_FILE_ORDERS)]
+    pub file_orders: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    /// Txs up to this order inclusive are exported.
+    exported_until: Option<i64>,
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestFile {
+    /// Relative to the export directory.
+    path: PathBuf,
+    from_order: i64,
+    to_order: i64,
+    txs: usize,
+}
+
+/// One line of an exported file.
+#[derive(Serialize)]
+struct ExportedTx<'a> {
+    order: i64,
+    /// Base58, as shown by explorers.
+    digest: String,
+    /// Version of the puller which stored the tx.
+    version: &'a str,
+    tx: SuiTransactionResponse,
+}
+
+pub async fn start(conf: Conf, args: Args) -> Result<()> {
+    if args.file_orders <= 0 || TXS_PARTITION_SIZE % args.file_orders != 0 {
+        bail!(
+            "File orders must be positive and divide {}",
+            TXS_PARTITION_SIZE
+        );
+    }
+
+    let db = conf.db()?;
+
+    let watermark = db::queue::select_watermark(&db, consts::JOB_KIND)
+        .await?
+        .ok_or_else(|| {
+        anyhow!("There's no watermark, no txs are final yet")
+    })?;
+    let to_order = args.to_order.map_or(watermark, |to| to.min(watermark));
+
+    fs::create_dir_all(&args.dir)
+        .await
+        .with_context(|| args.dir.display().to_string())?;
+    let mut manifest = read_manifest(&args.dir).await?;
+
+    let from_order = manifest
+        .exported_until
+        .map_or(args.from_order, |until| args.from_order.max(until + 1));
+    let (mut file_from, mut file_to) = file_range(from_order, args.file_orders);
+    if file_from != from_order {
+        bail!(
+            "Order {} is not at the start of a file, the closest one is {}",
+            from_order,
+            file_from
+        );
+    }
+
+    let mut exported = 0;
+    while file_to <= to_order {
+        let path = file_path(file_from, file_to);
+        let txs = export_file(&db, &args.dir.join(&path), file_from, file_to)
+            .await
+            .with_context(|| {
+                format!("Cannot export orders {}..={}", file_from, file_to)
+            })?;
+
+        // empty ranges don't get a file
+        if txs > 0 {
+            manifest.files.push(ManifestFile {
+                path,
+                from_order: file_from,
+                to_order: file_to,
+                txs,
+            });
+        }
+        manifest.exported_until = Some(file_to);
+        write_manifest(&args.dir, &manifest).await?;
+
+        exported += txs;
+        (file_from, file_to) = file_range(file_to + 1, args.file_orders);
+    }
+
+    match manifest.exported_until {
+        Some(until) => {
+            info!("Exported {} txs, up to order {}", exported, until)
+        }
+        None => info!("No complete range of orders below {}", to_order),
+    }
+
+    Ok(())
+}
+
+/// Writes txs with order in given range into given file. Returns how many txs
+/// were written. Nothing is written if there are no txs.
+async fn export_file(
+    db: &DbPool,
+    path: &Path,
+    from_order: i64,
+    to_order: i64,
+) -> Result<usize> {
+    // a file is either complete or not there, as with the manifest
+    let tmp_path = path.with_extension("ndjson.tmp");
+    // parent always exists, see `file_path`
+    let dir = tmp_path.parent().unwrap();
+    fs::create_dir_all(dir)
+        .await
+        .with_context(|| dir.display().to_string())?;
+    let mut file = File::create(&tmp_path)
+        .await
+        .with_context(|| tmp_path.display().to_string())?;
+
+    let mut txs = 0;
+    let mut from = from_order;
+    loop {
+        let page = db::select_txs_in_order_range(
+            db,
+            from,
+            to_order,
+            consts::EXPORT_PAGE_SIZE,
+        )
+        .await?;
+        let last_order = match page.last() {
+            Some(tx) => tx.order,
+            None => break,
+        };
+
+        let mut lines = vec![];
+        for tx in &page {
+            let decoded =
+                bincode::deserialize(&tx.data).with_context(|| {
+                    format!(
+                        "Cannot decode tx {} of version {}",
+                        tx.order, tx.version
+                    )
+                })?;
+            serde_json::to_writer(
+                &mut lines,
+                &ExportedTx {
+                    order: tx.order,
+                    digest: tx.digest.to_string(),
+                    version: &tx.version,
+                    tx: decoded,
+                },
+            )?;
+            lines.push(b'\n');
+        }
+        file.write_all(&lines).await?;
+
+        txs += page.len();
+        from = last_order + 1;
+    }
+
+    if txs == 0 {
+        drop(file);
+        fs::remove_file(&tmp_path).await?;
+    } else {
+        file.sync_all().await?;
+        fs::rename(&tmp_path, path)
+            .await
+            .with_context(|| path.display().to_string())?;
+    }
+
+    Ok(txs)
+}
+
+async fn read_manifest(dir: &Path) -> Result<Manifest> {
+    let path = dir.join(MANIFEST_FILE);
+    match fs::read(&path).await {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .with_context(|| format!("Invalid {}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Manifest::default()),
+        Err(e) => Err(e).context(path.display().to_string()),
+    }
+}
+
+async fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let path = dir.join(MANIFEST_FILE);
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(manifest)?)
+        .await
+        .with_context(|| tmp_path.display().to_string())?;
+    fs::rename(&tmp_path, &path)
+        .await
+        .with_context(|| path.display().to_string())?;
+
+    Ok(())
+}
+
+/// Range of orders, inclusive, of the file which given order belongs to.
+fn file_range(order: i64, file_orders: i64) -> (i64, i64) {
+    let from = order - order.rem_euclid(file_orders);
+    (from, from + file_orders - 1)
+}
+
+/// Relative to the export directory. Padded so that files sort by order.
+fn file_path(from_order: i64, to_order: i64) -> PathBuf {
+    let partition = from_order.div_euclid(TXS_PARTITION_SIZE);
+
+    PathBuf::from(format!("p{}", partition))
+        .join(format!("txs_{:019}_{:019}.ndjson", from_order, to_order))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_aligns_files() {
+        assert_eq!(file_range(0, 100), (0, 99));
+        assert_eq!(file_range(99, 100), (0, 99));
+        assert_eq!(file_range(100, 100), (100, 199));
+
+        assert_eq!(
+            file_path(TXS_PARTITION_SIZE, TXS_PARTITION_SIZE + 99),
+            PathBuf::from(format!(
+                "p1/txs_{:019}_{:019}.ndjson",
+                TXS_PARTITION_SIZE,
+                TXS_PARTITION_SIZE + 99
+            ))
+        );
+    }
+}
@@ -0,0 +1,18 @@
+//! HTTP server exposes the puller's inner state for monitoring.
+
+use rpc::CircuitBreaker;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use warp::Filter;
+
+/// Blocking operation which starts http server with paths:
+/// 1. GET /breaker => prints "closed"/"open"/"half-open"
+pub async fn start(addr: SocketAddr, breaker: Arc<CircuitBreaker>) {
+    // 1.
+    let breaker =
+        warp::path("breaker").map(move || format!("{}", breaker.state()));
+
+    let routes = warp::get().and(breaker);
+
+    warp::serve(routes).run(addr).await;
+}
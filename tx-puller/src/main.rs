@@ -7,18 +7,45 @@
 //! - https://webapp.io/blog/postgres-is-the-answer
 
 mod conf;
+mod export;
+mod http;
+mod partitions;
 mod prelude;
+mod reprocess;
+mod watermark;
 
-use conf::Conf;
+use clap::{Parser, Subcommand};
+use conf::{consts, Conf};
 use fastbloom_rs::{BloomFilter, Membership};
-use futures::future;
 use misc::sui_sdk::{
     rpc_types::{SuiEvent, SuiExecutionStatus, SuiTransactionResponse},
     types::object::Owner,
 };
+use misc::Shutdown;
 use prelude::*;
+use std::collections::HashMap;
 use std::iter;
 use std::ops::Not;
+use std::sync::Arc;
+use tokio::time::{sleep, Instant};
+
+/// Without a subcommand, the service pulls txs of unprocessed digests.
+#[derive(Parser)]
+#[clap(version, about)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Tests txs of already processed digests against new interest keys.
+    Reprocess(reprocess::Args),
+    /// Detaches or drops partitions of old txs.
+    DetachPartitions(partitions::Args),
+    /// Exports final txs into NDJSON files.
+    Export(export::Args),
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -26,71 +53,152 @@ async fn main() -> Result<()> {
 
     env_logger::init(); // set up with env RUST_LOG
 
+    let cli = Cli::parse();
+
     let conf = Conf::from_env().context("Cannot read env vars")?;
 
+    match cli.command {
+        Some(Command::Reprocess(args)) => {
+            return reprocess::start(conf, args).await
+        }
+        Some(Command::DetachPartitions(args)) => {
+            return partitions::start(conf, args).await
+        }
+        Some(Command::Export(args)) => return export::start(conf, args).await,
+        None => (),
+    };
+
+    let shutdown = Shutdown::listen()?;
+
     let sui = conf.rpc().await?;
-    let mut db = conf.db().await?;
+    let db = conf.storage()?;
+
+    if let Some(http_addr) = conf.http_addr {
+        tokio::spawn(http::start(http_addr, Arc::clone(sui.breaker())));
+    }
+
+    if let Some(every) = conf.watermark_interval {
+        let db = Arc::clone(&db);
+        tokio::spawn(async move {
+            if let Err(e) = watermark::start(db, every).await {
+                error!("Watermark task stopped: {:#}", e);
+            }
+        });
+    }
 
     // TODO: figure out population and updating
     let builder = fastbloom_rs::FilterBuilder::new(100_000_000, 0.01);
     let bloom = BloomFilter::new(builder);
 
-    loop {
-        let tx = db.transaction().await?;
+    // each batch is finished before we check for shutdown, see
+    // `process_next_batch` for why there's nothing to roll back in between
+    while !shutdown.is_requested() {
+        db.enqueue_from_digests(consts::JOB_KIND, conf.batch_size as i64)
+            .await?;
+        db.fail_abandoned(consts::JOB_KIND, conf.max_job_attempts)
+            .await?;
 
-        process_next_batch(&conf, &sui, &tx, &bloom).await?;
+        let claimed = process_next_batch(&conf, &sui, &*db, &bloom).await?;
 
-        tx.commit().await?;
+        if claimed == 0 {
+            tokio::select! {
+                _ = sleep(consts::SLEEP_ON_NO_JOBS) => (),
+                _ = shutdown.requested() => (),
+            };
+        }
     }
+
+    info!("Stopped pulling");
+
+    Ok(())
 }
 
-/// 1. Lock digests in db
-/// 2. Fetch details for those digests from rpc
+/// 1. Lease a batch of jobs in db
+/// 2. Fetch details for those digests from rpc in a single batch request
 /// 3. Check if that tx is of interest - that is, does it touch an object that
 /// some other part of the system cares about?
-/// 4. Interesting txs are written to db
-/// 5. All successfully fetched digest details are marked as processed
+/// 4. Jobs of all successfully fetched digest details are completed, the rest
+/// are returned to the queue
+/// 5. Interesting txs of jobs which we still held the lease of are written to
+/// db
+///
+/// 4. and 5. happen at once, see [`Storage::finish_jobs`]. No transaction is
+/// open while we wait for rpc.
+///
+/// Returns how many jobs were claimed.
 async fn process_next_batch(
     conf: &Conf,
-    sui: &SuiClient,
-    db: &impl GenericDbClient,
+    sui: &RpcClient,
+    db: &dyn Storage,
     bloom: &BloomFilter,
-) -> Result<()> {
+) -> Result<usize> {
+    let started_at = Instant::now();
+
     // 1.
-    let digests =
-        db::select_and_lock_unprocessed_digests(db, conf.batch_size).await?;
+    let jobs = db
+        .claim(
+            consts::JOB_KIND,
+            &conf.worker_id,
+            conf.batch_size as i64,
+            conf.job_lease,
+        )
+        .await?;
+    if jobs.is_empty() {
+        return Ok(0);
+    }
+    let claimed = jobs.len();
 
     // 2.
-    let responses = future::join_all(
-        digests.iter().map(|(_, digest)| rpc::fetch_tx(sui, digest)),
+    let responses = rpc::fetch_txs(
+        sui,
+        &jobs.iter().map(|job| job.digest).collect::<Vec<_>>(),
     )
     .await;
 
     // 3.
-    let mut ids_to_mark_processed = Vec::with_capacity(responses.len());
-    let txs = digests
-        .into_iter()
-        .zip(responses)
-        .filter_map(|(key, response)| Some((key, response.ok()?)))
-        .filter(|((id, _), response)| {
-            // at this point, if there's a failure, it's only in serialization
-            //
-            // there's something abnormal about the tx, report error to us but
-            // we expect that serialization will never fail
-            ids_to_mark_processed.push(*id);
-            is_tx_of_interest(bloom, response)
-        })
-        .map(|((id, digest), tx)| serialize_tx(id, digest, tx))
-        .collect::<Result<Vec<_>>>()?;
-
-    tokio::try_join!(
-        // 4.
-        db::insert_txs(db, &txs),
-        // 5.
-        db::mark_digests_as_processed(db, &ids_to_mark_processed)
-    )?;
+    let mut completed_jobs = Vec::with_capacity(claimed);
+    let mut failed_jobs = vec![];
+    let mut txs = HashMap::new();
+    for (job, response) in jobs.into_iter().zip(responses) {
+        let tx = match response {
+            Ok(tx) => tx,
+            Err(e) => {
+                warn!("Cannot fetch tx of digest {}: {}", job.digest_id, e);
+                failed_jobs.push(job.id);
+                continue;
+            }
+        };
 
-    Ok(())
+        completed_jobs.push(job.id);
+        if is_tx_of_interest(bloom, &tx) {
+            // there's something abnormal about the tx if it cannot be
+            // serialized, report error to us but we expect that serialization
+            // will never fail
+            txs.insert(job.id, serialize_tx(job.digest_id, job.digest, tx)?);
+        }
+    }
+
+    // 4. and 5.
+    let fetched_jobs = completed_jobs.len();
+    let completed_jobs = db
+        .finish_jobs(
+            &conf.worker_id,
+            &completed_jobs,
+            &failed_jobs,
+            conf.max_job_attempts,
+            txs,
+        )
+        .await?;
+    let lost_jobs = fetched_jobs - completed_jobs.len();
+    if lost_jobs > 0 {
+        warn!(
+            "Lease of {} jobs expired after {:?}, consider raising JOB_LEASE_MS",
+            lost_jobs,
+            started_at.elapsed()
+        );
+    }
+
+    Ok(claimed)
 }
 
 /// The tx data is serialized with bincode and versioned in the db.
@@ -0,0 +1,38 @@
+//! Txs are stored in partitions by ranges of their order, see
+//! [`db::partitions`]. Partitions of old txs which no consumer reads anymore
+//! can be detached, e.g. to be dumped elsewhere, or dropped.
+
+use crate::conf::Conf;
+use crate::prelude::*;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Partitions which only hold txs with order lower than this one are
+    /// detached.
+    #[clap(long)]
+    pub below_order: i64,
+    /// Drops the detached partitions.
+    #[clap(long)]
+    pub drop: bool,
+}
+
+pub async fn start(conf: Conf, args: Args) -> Result<()> {
+    let db = conf.db()?;
+
+    let partitions = db::partitions::detach_txs_partitions_below(
+        &db,
+        args.below_order,
+        !args.drop,
+    )
+    .await?;
+
+    if partitions.is_empty() {
+        info!("No partitions below order {}", args.below_order);
+    } else if args.drop {
+        info!("Dropped partitions {}", partitions.join(", "));
+    } else {
+        info!("Detached partitions {}", partitions.join(", "));
+    }
+
+    Ok(())
+}
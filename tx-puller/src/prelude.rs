@@ -1,7 +1,5 @@
 pub use anyhow::{anyhow, bail, Context, Result};
+pub use db::{Pool as DbPool, Storage};
 pub use log::{error, info, warn};
-pub use misc::sui_sdk::SuiClient;
 pub use misc::{Digest, SeqNum};
-pub use tokio_postgres::{
-    Client as DbClient, GenericClient as GenericDbClient,
-};
+pub use rpc::Client as RpcClient;
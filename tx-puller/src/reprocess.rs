@@ -0,0 +1,148 @@
+//! The puller only tests digests which are not processed yet against the
+//! interest set. When we start watching a new address, txs of digests which
+//! were already processed are never looked at again.
+//!
+//! Reprocessing walks digests of a bounded id range whose puller jobs are done
+//! and tests their txs against a set of new interest keys only. Matching txs
+//! are inserted unless they're already stored. It's safe to run the same
+//! reprocessing again, e.g. after some txs failed to fetch.
+//!
+//! With `RPC_CACHE_DIR` set, txs which were fetched before aren't requested
+//! from the node again.
+
+use crate::conf::{consts, Conf};
+use crate::prelude::*;
+use crate::{is_tx_of_interest, serialize_tx};
+use fastbloom_rs::{BloomFilter, FilterBuilder, Membership};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Digest id to start reprocessing from, inclusive.
+    #[clap(long)]
+    pub from_id: i64,
+    /// Digest id to reprocess until, inclusive.
+    #[clap(long)]
+    pub to_id: i64,
+    /// Comma separated hex encoded keys which are of interest, e.g. addresses
+    /// or object ids.
+    #[clap(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_key,
+        required = true
+    )]
+    pub keys: Vec<Vec<u8>>,
+    /// How many digests are reprocessed at once. Defaults to `BATCH_SIZE`.
+    #[clap(long)]
+    pub batch_size: Option<usize>,
+}
+
+pub async fn start(conf: Conf, args: Args) -> Result<()> {
+    if args.from_id > args.to_id {
+        bail!("Empty id range {}..={}", args.from_id, args.to_id);
+    }
+    let batch_size = args.batch_size.unwrap_or(conf.batch_size);
+    if batch_size == 0 {
+        bail!("Batch size must be positive");
+    }
+
+    let sui = conf.rpc().await?;
+    let db = conf.db()?;
+
+    let bloom = new_keys_filter(&args.keys);
+
+    let mut from_id = args.from_id;
+    let mut reprocessed = 0;
+    let mut inserted = 0;
+    let mut failed = 0;
+    loop {
+        let jobs = db::queue::select_done_in_digest_id_range(
+            &db,
+            consts::JOB_KIND,
+            from_id,
+            args.to_id,
+            batch_size as i64,
+        )
+        .await?;
+        let last_id = match jobs.last() {
+            Some(job) => job.digest_id,
+            None => break,
+        };
+        from_id = last_id + 1;
+
+        let responses = rpc::fetch_txs(
+            &sui,
+            &jobs.iter().map(|job| job.digest).collect::<Vec<_>>(),
+        )
+        .await;
+
+        let mut txs = vec![];
+        for (job, response) in jobs.into_iter().zip(responses) {
+            match response {
+                Ok(tx) if is_tx_of_interest(&bloom, &tx) => {
+                    txs.push(serialize_tx(job.digest_id, job.digest, tx)?)
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    warn!("Cannot fetch tx of digest {}: {}", job.digest_id, e);
+                    failed += 1;
+                }
+            }
+            reprocessed += 1;
+        }
+
+        inserted += db::insert_missing_txs(&db, &txs).await?;
+        info!(
+            "Reprocessed {} digests up to id {}, inserted {} txs",
+            reprocessed, last_id, inserted
+        );
+    }
+
+    if failed > 0 {
+        bail!(
+            "Txs of {} digests could not be fetched, rerun the reprocessing",
+            failed
+        );
+    }
+
+    Ok(())
+}
+
+fn new_keys_filter(keys: &[Vec<u8>]) -> BloomFilter {
+    let builder = FilterBuilder::new(keys.len() as u64, 0.01);
+    let mut bloom = BloomFilter::new(builder);
+    for key in keys {
+        bloom.add(key);
+    }
+
+    bloom
+}
+
+fn parse_key(s: &str) -> Result<Vec<u8>> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if s.is_empty() || s.len() % 2 != 0 || !s.is_ascii() {
+        bail!("Key '{}' must be non-empty hex with even length", s);
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .with_context(|| format!("Key '{}' is not hex", s))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_hex_keys() {
+        assert_eq!(parse_key("0x0aff").unwrap(), vec![0x0a, 0xff]);
+        assert_eq!(parse_key("0aff").unwrap(), vec![0x0a, 0xff]);
+        assert!(parse_key("0af").is_err());
+        assert!(parse_key("zz").is_err());
+        assert!(parse_key("").is_err());
+    }
+}
@@ -0,0 +1,26 @@
+//! Pullers process jobs in parallel, hence txs are not inserted in their
+//! order. When enabled, the puller periodically advances the watermark of its
+//! jobs, see [`db::queue::advance_watermark`]. Consumers which tail `txs` by
+//! their `order` should only read up to the watermark, anything above it might
+//! still be filled in.
+//!
+//! Txs of failed jobs are missing below the watermark for good.
+
+use crate::conf::consts;
+use crate::prelude::*;
+use std::sync::Arc;
+use tokio::time::{interval, Duration};
+
+/// Runs forever. Errors are only logged, the next tick tries again.
+pub async fn start(db: Arc<dyn Storage>, every: Duration) -> Result<()> {
+    let mut ticks = interval(every);
+    loop {
+        ticks.tick().await;
+
+        match db.advance_watermark(consts::JOB_KIND).await {
+            Ok(Some(digest_id)) => info!("Watermark at digest {}", digest_id),
+            Ok(None) => (),
+            Err(e) => error!("Cannot advance watermark: {:#}", e),
+        }
+    }
+}